@@ -0,0 +1,71 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// IRCv3 capabilities understood by the server.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Capability {
+    Batch,
+    LabeledResponse,
+    AwayNotify,
+    AccountNotify,
+    ExtendedJoin,
+    ChgHost,
+    SetName,
+    MultiPrefix,
+    UserhostInNames,
+    MessageTags,
+    InviteNotify,
+    Sasl,
+}
+
+impl Capability {
+    /// Every capability, in the order they are advertised in `CAP LS`.
+    pub const ALL: &'static [Capability] = &[
+        Capability::Batch,
+        Capability::LabeledResponse,
+        Capability::AwayNotify,
+        Capability::AccountNotify,
+        Capability::ExtendedJoin,
+        Capability::ChgHost,
+        Capability::SetName,
+        Capability::MultiPrefix,
+        Capability::UserhostInNames,
+        Capability::MessageTags,
+        Capability::InviteNotify,
+        Capability::Sasl,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Batch => "batch",
+            Capability::LabeledResponse => "labeled-response",
+            Capability::AwayNotify => "away-notify",
+            Capability::AccountNotify => "account-notify",
+            Capability::ExtendedJoin => "extended-join",
+            Capability::ChgHost => "chghost",
+            Capability::SetName => "setname",
+            Capability::MultiPrefix => "multi-prefix",
+            Capability::UserhostInNames => "userhost-in-names",
+            Capability::MessageTags => "message-tags",
+            Capability::InviteNotify => "invite-notify",
+            Capability::Sasl => "sasl",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or(())
+    }
+}
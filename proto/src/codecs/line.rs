@@ -27,7 +27,7 @@ impl LineCodec {
             .map(|enc| LineCodec {
                 encoding: enc,
                 next_index: 0,
-                max_length: max_length,
+                max_length,
             })
             .ok_or_else(|| LineCodecError::InvalidEncoding(label.to_string()))
     }
@@ -62,7 +62,7 @@ impl Decoder for LineCodec {
             match buf.last() {
                 Some(b'\r') => { 
                     buf.truncate(len - 1);
-                    len = len - 1
+                    len -= 1
                 }
                 None => return Ok(Some(String::new())),
                 _ => break
@@ -74,15 +74,10 @@ impl Decoder for LineCodec {
             .decode(&buf.freeze(), encoding::DecoderTrap::Replace)
         {
             Ok(data) => Ok(Some(data)),
-            Err(data) => {
-                return Err(LineCodecError::Io(
-                    io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        &format!("Failed to decode {} as {}.", data, self.encoding.name())[..],
-                    )
-                    .into(),
-                ));
-            }
+            Err(data) => Err(LineCodecError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Failed to decode {} as {}.", data, self.encoding.name())[..],
+            ))),
         }
     }
 }
@@ -94,13 +89,10 @@ impl Encoder<String> for LineCodec {
             .encoding
             .encode(&msg, EncoderTrap::Replace)
             .map_err(|data| {
-                LineCodecError::Io(
-                    io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        &format!("Failed to decode {} as {}.", data, self.encoding.name())[..],
-                    )
-                    .into(),
-                )
+                LineCodecError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    &format!("Failed to decode {} as {}.", data, self.encoding.name())[..],
+                ))
             }) {
             Ok(data) => {
                 if data.len() > self.max_length {
@@ -108,11 +100,11 @@ impl Encoder<String> for LineCodec {
                 }
                 dst.reserve(self.max_length);
                 dst.put_slice(&data);
-                return Ok(());
+                Ok(())
             }
             Err(e) => Err(e),
         };
-        return data;
+        data
     }
 }
 
@@ -1,11 +1,14 @@
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::message::Message;
+use crate::message::{Message, MAX_BODY_LENGTH};
 use memchr::memmem;
 
 use super::LineCodec;
 use crate::error::{MessageParseError, ProtocolError};
 
+/// Server tags may take up to 8191 bytes on top of the message body.
+const MAX_LINE_LENGTH: usize = 8191 + MAX_BODY_LENGTH;
+
 #[derive(Debug)]
 pub struct MessageCodec {
     inner: LineCodec,
@@ -13,7 +16,7 @@ pub struct MessageCodec {
 
 impl MessageCodec {
     pub fn new(label: &str) -> Result<MessageCodec, MessageParseError> {
-        let line = LineCodec::new(label).map_err(|e| MessageParseError::LineError {
+        let line = LineCodec::new_max_length(label, MAX_LINE_LENGTH).map_err(|e| MessageParseError::LineError {
             string: "failed to make codec".to_owned(),
             cause: e,
         })?;
@@ -49,8 +52,10 @@ impl Encoder<Message> for MessageCodec {
     }
 }
 
+/// Lines which fail to parse are yielded as errors rather than failing the
+/// decoder, as a failed decoder ends the stream and with it the connection.
 impl Decoder for MessageCodec {
-    type Item = Message;
+    type Item = Result<Message, ProtocolError>;
     type Error = ProtocolError;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
@@ -63,6 +68,6 @@ impl Decoder for MessageCodec {
                     cause: e,
                 },
             })
-            .and_then(|res| res.map_or(Ok(None), |msg| msg.parse::<Message>().map(Some)))
+            .map(|res| res.map(|msg| msg.parse::<Message>()))
     }
 }
@@ -1,6 +1,6 @@
-use std::string;
+use std::str::FromStr;
 
-use crate::{codecs::message, error::MessageParseError, response::Response};
+use crate::{error::MessageParseError, response::Response};
 
 //use macros;
 
@@ -18,16 +18,99 @@ pub enum Command {
     NOTICE(String, String),
     PING(String, Option<String>),
     PONG(String, Option<String>),
+    QUIT(Option<String>),
+    ERROR(String),
+
+    /* Channels, Keys, Account or Realname (extended-join) */
+    JOIN(String, Option<String>, Option<String>),
+    PART(String, Option<String>),
+    /* Target, Modes, Parameters */
+    MODE(String, Option<String>, Option<Vec<String>>),
+    /* Nick, Channel */
+    INVITE(String, String),
+    /* Channels, Users, Comment */
+    KICK(String, String, Option<String>),
+    AWAY(Option<String>),
+    NAMES(Option<String>),
+    /* Mask, Options */
+    WHO(Option<String>, Option<String>),
+    /* Server, Nicks */
+    WHOIS(Option<String>, String),
+    /* Name, Password */
+    OPER(String, String),
+    /* Password, Gateway, Hostname, IP, Options */
+    WEBIRC(String, String, String, String, Option<String>),
+    REHASH,
+
+    /* IRCv3 */
+    /* Target, Subcommand, Argument, Argument */
+    CAP(Option<String>, CapSubCommand, Option<String>, Option<String>),
+    /* Reference tag (+/-), Type, Parameters */
+    BATCH(String, Option<String>, Option<Vec<String>>),
+    ACK,
+    ACCOUNT(String),
+    /* New username, New hostname */
+    CHGHOST(String, String),
+    SETNAME(String),
+    /* Subcommand (+, -, C, L, S), Targets */
+    MONITOR(String, Option<String>),
+    TAGMSG(String),
+    /* Mechanism, or base64 data */
+    AUTHENTICATE(String),
     RAW(String)
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CapSubCommand {
+    LS,
+    LIST,
+    REQ,
+    ACK,
+    NAK,
+    END,
+    NEW,
+    DEL,
+}
+
+impl CapSubCommand {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            CapSubCommand::LS => "LS",
+            CapSubCommand::LIST => "LIST",
+            CapSubCommand::REQ => "REQ",
+            CapSubCommand::ACK => "ACK",
+            CapSubCommand::NAK => "NAK",
+            CapSubCommand::END => "END",
+            CapSubCommand::NEW => "NEW",
+            CapSubCommand::DEL => "DEL",
+        }
+    }
+}
+
+impl FromStr for CapSubCommand {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "LS" => Ok(CapSubCommand::LS),
+            "LIST" => Ok(CapSubCommand::LIST),
+            "REQ" => Ok(CapSubCommand::REQ),
+            "ACK" => Ok(CapSubCommand::ACK),
+            "NAK" => Ok(CapSubCommand::NAK),
+            "END" => Ok(CapSubCommand::END),
+            "NEW" => Ok(CapSubCommand::NEW),
+            "DEL" => Ok(CapSubCommand::DEL),
+            _ => Err(()),
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 impl Command {
     pub fn Pass<S: Into<String>>(password: S) -> Command {
         Command::PASS(password.into())
     }
     pub fn Nick<S: Into<String>>(nick: S, hops: Option<i32>) -> Command {
-        Command::NICK(nick.into(), hops.map(|n| n.into()))
+        Command::NICK(nick.into(), hops)
     }
     
     pub fn User<S: Into<String>>(user: S, host: S, server: S, real: S) -> Command {
@@ -51,6 +134,96 @@ impl Command {
         Command::PONG(target.into(), target2.map(|s| s.into()))
     }
 
+    pub fn Quit<S: Into<String>>(reason: Option<S>) -> Command {
+        Command::QUIT(reason.map(|s| s.into()))
+    }
+    pub fn Error<S: Into<String>>(reason: S) -> Command {
+        Command::ERROR(reason.into())
+    }
+    pub fn Join<S: Into<String>>(channels: S, keys: Option<S>, real: Option<S>) -> Command {
+        Command::JOIN(channels.into(), keys.map(|s| s.into()), real.map(|s| s.into()))
+    }
+    pub fn Part<S: Into<String>>(channels: S, reason: Option<S>) -> Command {
+        Command::PART(channels.into(), reason.map(|s| s.into()))
+    }
+    pub fn Mode<S: Into<String>>(target: S, modes: Option<S>, params: Option<Vec<S>>) -> Command {
+        Command::MODE(
+            target.into(),
+            modes.map(|s| s.into()),
+            params.map(|o| o.into_iter().map(|s: S| s.into()).collect()),
+        )
+    }
+    pub fn Invite<S: Into<String>>(nick: S, channel: S) -> Command {
+        Command::INVITE(nick.into(), channel.into())
+    }
+    pub fn Kick<S: Into<String>>(channels: S, users: S, comment: Option<S>) -> Command {
+        Command::KICK(channels.into(), users.into(), comment.map(|s| s.into()))
+    }
+    pub fn Away<S: Into<String>>(message: Option<S>) -> Command {
+        Command::AWAY(message.map(|s| s.into()))
+    }
+    pub fn Names<S: Into<String>>(channels: Option<S>) -> Command {
+        Command::NAMES(channels.map(|s| s.into()))
+    }
+    pub fn Monitor<S: Into<String>>(subcommand: S, targets: Option<S>) -> Command {
+        Command::MONITOR(subcommand.into(), targets.map(|s| s.into()))
+    }
+    pub fn Tagmsg<S: Into<String>>(target: S) -> Command {
+        Command::TAGMSG(target.into())
+    }
+    pub fn Who<S: Into<String>>(mask: Option<S>, options: Option<S>) -> Command {
+        Command::WHO(mask.map(|s| s.into()), options.map(|s| s.into()))
+    }
+    pub fn Whois<S: Into<String>>(server: Option<S>, nicks: S) -> Command {
+        Command::WHOIS(server.map(|s| s.into()), nicks.into())
+    }
+    pub fn Oper<S: Into<String>>(name: S, password: S) -> Command {
+        Command::OPER(name.into(), password.into())
+    }
+    pub fn Webirc<S: Into<String>>(password: S, gateway: S, hostname: S, ip: S, options: Option<S>) -> Command {
+        Command::WEBIRC(
+            password.into(),
+            gateway.into(),
+            hostname.into(),
+            ip.into(),
+            options.map(|s| s.into()),
+        )
+    }
+
+    pub fn Cap<S: Into<String>>(
+        target: Option<S>,
+        subcommand: CapSubCommand,
+        arg: Option<S>,
+        arg2: Option<S>,
+    ) -> Command {
+        Command::CAP(
+            target.map(|s| s.into()),
+            subcommand,
+            arg.map(|s| s.into()),
+            arg2.map(|s| s.into()),
+        )
+    }
+    pub fn Batch<S: Into<String>>(reference: S, kind: Option<S>, params: Option<Vec<S>>) -> Command {
+        Command::BATCH(
+            reference.into(),
+            kind.map(|s| s.into()),
+            params.map(|o| o.into_iter().map(|s: S| s.into()).collect()),
+        )
+    }
+
+    pub fn Account<S: Into<String>>(account: S) -> Command {
+        Command::ACCOUNT(account.into())
+    }
+    pub fn Chghost<S: Into<String>>(user: S, host: S) -> Command {
+        Command::CHGHOST(user.into(), host.into())
+    }
+    pub fn Setname<S: Into<String>>(real: S) -> Command {
+        Command::SETNAME(real.into())
+    }
+    pub fn Authenticate<S: Into<String>>(data: S) -> Command {
+        Command::AUTHENTICATE(data.into())
+    }
+
     pub fn Raw<S: Into<String>>(raw: S) -> Command {
         Command::RAW(raw.into())
     }
@@ -120,6 +293,151 @@ impl Command {
                 }
                 _ => Err(Response::ErrNeedMoreParams(command).into()),
             },
+            "QUIT" => match args.len() {
+                0 => Ok(Command::QUIT(None)),
+                1 => Ok(Command::Quit(Some(args[0]))),
+                _ => Err(MessageParseError::InvalidArgumentCount),
+            },
+            "ERROR" => {
+                if args.len() == 1 {
+                    Ok(Command::Error(args[0]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "JOIN" => match args.len() {
+                1 => Ok(Command::Join(args[0], None, None)),
+                2 => Ok(Command::Join(args[0], Some(args[1]), None)),
+                3 => Ok(Command::Join(args[0], Some(args[1]), Some(args[2]))),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "PART" => match args.len() {
+                1 => Ok(Command::Part(args[0], None)),
+                2 => Ok(Command::Part(args[0], Some(args[1]))),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "MODE" => match args.len() {
+                0 => Err(Response::ErrNeedMoreParams(command).into()),
+                1 => Ok(Command::Mode(args[0], None, None)),
+                2 => Ok(Command::Mode(args[0], Some(args[1]), None)),
+                _ => Ok(Command::Mode(args[0], Some(args[1]), Some(args[2..].to_vec()))),
+            },
+            "INVITE" => {
+                if args.len() == 2 {
+                    Ok(Command::Invite(args[0], args[1]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "KICK" => match args.len() {
+                2 => Ok(Command::Kick(args[0], args[1], None)),
+                3 => Ok(Command::Kick(args[0], args[1], Some(args[2]))),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "AWAY" => match args.len() {
+                0 => Ok(Command::AWAY(None)),
+                1 if args[0].is_empty() => Ok(Command::AWAY(None)),
+                1 => Ok(Command::Away(Some(args[0]))),
+                _ => Err(MessageParseError::InvalidArgumentCount),
+            },
+            "NAMES" => match args.len() {
+                0 => Ok(Command::NAMES(None)),
+                1 => Ok(Command::Names(Some(args[0]))),
+                _ => Err(MessageParseError::InvalidArgumentCount),
+            },
+            "WHO" => match args.len() {
+                0 => Ok(Command::WHO(None, None)),
+                1 => Ok(Command::Who(Some(args[0]), None)),
+                2 => Ok(Command::Who(Some(args[0]), Some(args[1]))),
+                _ => Err(MessageParseError::InvalidArgumentCount),
+            },
+            "WHOIS" => match args.len() {
+                1 => Ok(Command::Whois(None, args[0])),
+                2 => Ok(Command::Whois(Some(args[0]), args[1])),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "OPER" => {
+                if args.len() == 2 {
+                    Ok(Command::Oper(args[0], args[1]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "WEBIRC" => match args.len() {
+                4 => Ok(Command::Webirc(args[0], args[1], args[2], args[3], None)),
+                5 => Ok(Command::Webirc(args[0], args[1], args[2], args[3], Some(args[4]))),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "MONITOR" => match args.len() {
+                1 => Ok(Command::Monitor(args[0], None)),
+                2 => Ok(Command::Monitor(args[0], Some(args[1]))),
+                _ => Err(Response::ErrNeedMoreParams(command).into()),
+            },
+            "TAGMSG" => {
+                if args.len() == 1 {
+                    Ok(Command::Tagmsg(args[0]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "ACCOUNT" => {
+                if args.len() == 1 {
+                    Ok(Command::Account(args[0]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "CHGHOST" => {
+                if args.len() == 2 {
+                    Ok(Command::Chghost(args[0], args[1]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "SETNAME" => {
+                if args.len() == 1 {
+                    Ok(Command::Setname(args[0]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "AUTHENTICATE" => {
+                if args.len() == 1 {
+                    Ok(Command::Authenticate(args[0]))
+                } else {
+                    Err(Response::ErrNeedMoreParams(command).into())
+                }
+            }
+            "CAP" => {
+                if args.is_empty() {
+                    return Err(Response::ErrNeedMoreParams(command).into());
+                }
+                if let Ok(sub) = args[0].parse::<CapSubCommand>() {
+                    Ok(Command::Cap(None, sub, args.get(1).copied(), args.get(2).copied()))
+                } else if let Some(Ok(sub)) = args.get(1).map(|s| s.parse::<CapSubCommand>()) {
+                    Ok(Command::Cap(
+                        Some(args[0]),
+                        sub,
+                        args.get(2).copied(),
+                        args.get(3).copied(),
+                    ))
+                } else {
+                    Err(Response::ErrInvalidCapCmd(args[0].to_owned()).into())
+                }
+            }
+            "BATCH" => {
+                if args.is_empty() || (args[0].starts_with('+') && args.len() < 2) {
+                    return Err(Response::ErrNeedMoreParams(command).into());
+                }
+                if args[0].starts_with('+') {
+                    let params = if args.len() > 2 { Some(args[2..].to_vec()) } else { None };
+                    Ok(Command::Batch(args[0], Some(args[1]), params))
+                } else {
+                    Ok(Command::Batch(args[0], None, None))
+                }
+            }
+            "ACK" => Ok(Command::ACK),
+            "REHASH" => Ok(Command::REHASH),
             _ => Err(Response::ErrNoSuchCommand(command).into()),
         }
     }
@@ -141,41 +459,96 @@ fn stringify(cmd: &str, args: &[&str]) -> String {
     }
 }
 
-fn stringify_owned(cmd: &str, args: &[String]) -> String {
-    match args.split_last() {
-        Some((suffix, args)) => {
-            let args = args.join(" ");
-            let sp = if args.is_empty() { "" } else { " " };
-            let co = if suffix.is_empty() || suffix.contains(' ') || suffix.starts_with(':') {
-                ":"
-            } else {
-                ""
-            };
-            format!("{}{}{} {}{}", cmd, sp, args, co, suffix)
-        }
-        None => cmd.to_string(),
-    }
-}
-
 impl<'a> From<&'a Command> for String {
     fn from(cmd: &'a Command) -> String {
         match *cmd {
-            Command::PASS(ref password) => stringify("PASSWORD", &[&password]),
+            Command::PASS(ref password) => stringify("PASSWORD", &[password]),
             Command::NICK(ref nick, None) => stringify("NICK", &[nick]),
             Command::NICK(ref nick, Some(ref hops)) => stringify("NICK", &[nick, &hops.to_string()]),
             Command::USER(ref u, ref h, ref s, ref r) => stringify("USER", &[u,h,s,r]),
             Command::PRIVMSG(ref recip, ref message, Some(ref ccs)) => stringify(
                 "privmsg",
-                &[format!("{},{}", recip, ccs.join(",")).as_ref(), &message],
+                &[format!("{},{}", recip, ccs.join(",")).as_ref(), message],
             ),
             Command::PRIVMSG(ref recip, ref message, None) => {
-                stringify("PRIVMSG", &[&recip, &message])
+                stringify("PRIVMSG", &[recip, message])
+            }
+            Command::NOTICE(ref nick, ref msg) => stringify("NOTICE", &[nick, msg]),
+            Command::PING(ref sv1, Some(ref sv2)) => stringify("PING", &[sv1, sv2]),
+            Command::PING(ref sv1, None) => stringify("PING", &[sv1]),
+            Command::PONG(ref daemon, Some(ref daemon2)) => stringify("PING", &[daemon, daemon2]),
+            Command::PONG(ref sv1, None) => stringify("PONG", &[sv1]),
+            Command::QUIT(Some(ref reason)) => stringify("QUIT", &[reason]),
+            Command::QUIT(None) => stringify("QUIT", &[]),
+            Command::ERROR(ref reason) => stringify("ERROR", &[reason]),
+            Command::JOIN(ref chans, Some(ref keys), Some(ref real)) => stringify("JOIN", &[chans, keys, real]),
+            Command::JOIN(ref chans, Some(ref keys), None) => stringify("JOIN", &[chans, keys]),
+            Command::JOIN(ref chans, None, Some(ref real)) => stringify("JOIN", &[chans, "*", real]),
+            Command::JOIN(ref chans, None, None) => stringify("JOIN", &[chans]),
+            Command::PART(ref chans, Some(ref reason)) => stringify("PART", &[chans, reason]),
+            Command::PART(ref chans, None) => stringify("PART", &[chans]),
+            Command::MODE(ref target, ref modes, ref params) => {
+                let mut args: Vec<&str> = vec![target];
+                if let Some(modes) = modes {
+                    args.push(modes);
+                }
+                if let Some(params) = params {
+                    args.extend(params.iter().map(|p| p.as_str()));
+                }
+                stringify("MODE", &args)
+            }
+            Command::INVITE(ref nick, ref chan) => stringify("INVITE", &[nick, chan]),
+            Command::KICK(ref chans, ref users, Some(ref comment)) => stringify("KICK", &[chans, users, comment]),
+            Command::KICK(ref chans, ref users, None) => stringify("KICK", &[chans, users]),
+            Command::AWAY(Some(ref msg)) => stringify("AWAY", &[msg]),
+            Command::AWAY(None) => stringify("AWAY", &[]),
+            Command::NAMES(Some(ref chans)) => stringify("NAMES", &[chans]),
+            Command::NAMES(None) => stringify("NAMES", &[]),
+            Command::WHO(Some(ref mask), Some(ref opts)) => stringify("WHO", &[mask, opts]),
+            Command::WHO(Some(ref mask), None) => stringify("WHO", &[mask]),
+            Command::WHO(None, _) => stringify("WHO", &[]),
+            Command::WHOIS(Some(ref server), ref nicks) => stringify("WHOIS", &[server, nicks]),
+            Command::WHOIS(None, ref nicks) => stringify("WHOIS", &[nicks]),
+            Command::OPER(ref name, ref password) => stringify("OPER", &[name, password]),
+            Command::WEBIRC(ref password, ref gateway, ref hostname, ref ip, Some(ref options)) => {
+                stringify("WEBIRC", &[password, gateway, hostname, ip, options])
+            }
+            Command::WEBIRC(ref password, ref gateway, ref hostname, ref ip, None) => {
+                stringify("WEBIRC", &[password, gateway, hostname, ip])
+            }
+            Command::MONITOR(ref sub, Some(ref targets)) => stringify("MONITOR", &[sub, targets]),
+            Command::MONITOR(ref sub, None) => stringify("MONITOR", &[sub]),
+            Command::TAGMSG(ref target) => stringify("TAGMSG", &[target]),
+            Command::CAP(ref target, ref sub, ref arg, ref arg2) => {
+                let mut args: Vec<&str> = Vec::new();
+                if let Some(target) = target {
+                    args.push(target);
+                }
+                args.push(sub.to_str());
+                if let Some(arg) = arg {
+                    args.push(arg);
+                }
+                if let Some(arg2) = arg2 {
+                    args.push(arg2);
+                }
+                stringify("CAP", &args)
+            }
+            Command::BATCH(ref reference, ref kind, ref params) => {
+                let mut args: Vec<&str> = vec![reference];
+                if let Some(kind) = kind {
+                    args.push(kind);
+                }
+                if let Some(params) = params {
+                    args.extend(params.iter().map(|p| p.as_str()));
+                }
+                stringify("BATCH", &args)
             }
-            Command::NOTICE(ref nick, ref msg) => stringify("NOTICE", &[&nick, &msg]),
-            Command::PING(ref sv1, Some(ref sv2)) => stringify("PING", &[&sv1, &sv2]),
-            Command::PING(ref sv1, None) => stringify("PING", &[&sv1]),
-            Command::PONG(ref daemon, Some(ref daemon2)) => stringify("PING", &[&daemon, &daemon2]),
-            Command::PONG(ref sv1, None) => stringify("PONG", &[&sv1]),
+            Command::ACK => stringify("ACK", &[]),
+            Command::REHASH => stringify("REHASH", &[]),
+            Command::ACCOUNT(ref account) => stringify("ACCOUNT", &[account]),
+            Command::CHGHOST(ref user, ref host) => stringify("CHGHOST", &[user, host]),
+            Command::SETNAME(ref real) => stringify("SETNAME", &[real]),
+            Command::AUTHENTICATE(ref data) => stringify("AUTHENTICATE", &[data]),
             Command::RAW(ref raw) => stringify(raw, &[]),
         }
     }
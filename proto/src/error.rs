@@ -8,11 +8,13 @@ pub type Result<T, E = ProtocolError> = ::std::result::Result<T, E>;
 #[derive(Debug, Error)]
 pub enum ProtocolError {
     #[error("channel error occurred")]
-    SendError(#[source] mpsc::error::SendError<Message>),
+    SendError(#[source] Box<mpsc::error::SendError<Message>>),
     #[error("an io error occurred")]
     Io(#[source] std::io::Error),
     #[error("ping timeout reached")]
     PingTimeout,
+    #[error("SendQ exceeded")]
+    SendQExceeded,
     #[error("server error")]
     ServerError,
     #[error("invalid message: {}", string)]
@@ -29,6 +31,12 @@ impl From<std::io::Error> for ProtocolError {
     }
 }
 
+impl From<mpsc::error::SendError<Message>> for ProtocolError {
+    fn from(e: mpsc::error::SendError<Message>) -> ProtocolError {
+        ProtocolError::SendError(Box::new(e))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum MessageParseError {
     #[error("empty message")]
@@ -40,7 +48,7 @@ pub enum MessageParseError {
     #[error("no line delimiter")]
     MissingCRLF,
     #[error("command error response")]
-    ErrResponse(Response),
+    ErrResponse(Box<Response>),
     #[error("error decoding line: {}", string)]
     LineError {
         string: String,
@@ -51,7 +59,7 @@ pub enum MessageParseError {
 
 impl From<Response> for MessageParseError {
     fn from(value: Response) -> Self {
-        MessageParseError::ErrResponse(value)
+        MessageParseError::ErrResponse(Box::new(value))
     }
 }
 
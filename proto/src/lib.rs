@@ -1,5 +1,4 @@
-#![feature(arbitrary_enum_discriminant)]
-
+pub mod caps;
 pub mod codecs;
 pub mod command;
 pub mod error;
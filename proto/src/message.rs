@@ -2,7 +2,7 @@ use super::command;
 use super::response;
 use crate::error::{MessageParseError, ProtocolError};
 use crate::prefix::Prefix;
-use std::{fmt::Write, str::FromStr};
+use std::{fmt::{self, Write}, str::FromStr};
 
 #[non_exhaustive]
 #[derive(Clone, PartialEq, Debug)]
@@ -11,31 +11,90 @@ pub enum MessageContents {
     Response(response::Response),
 }
 
-impl MessageContents {
-    pub fn to_string(&self) -> String {
+/// The line without its CRLF, which `Message` adds.
+impl fmt::Display for MessageContents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MessageContents::Command(command) => {
-                let mut ret = String::new();
-                let cmd: String = From::from(command);
-                //TODO: Move to config or somthing i don't know.
-                ret.push_str(&cmd);
-                ret.push_str("\r\n");
-                ret
-            }
-            MessageContents::Response(response) => {
-                let mut ret = String::new();
-                let cmd: String = From::from(response);
-                //TODO: Move to config or somthing i don't know.
-                ret.push_str(&cmd);
-                ret.push_str("\r\n");
-                ret
-            }
-            _ => "".to_owned(),
+            MessageContents::Command(command) => f.write_str(&String::from(command)),
+            MessageContents::Response(response) => write!(f, "{}", response),
         }
     }
 }
+
+/// Maximum size of the tag section sent by a client, including the leading
+/// `@` and trailing space.
+pub const MAX_CLIENT_TAGS_LENGTH: usize = 4096;
+/// Maximum size of a message excluding its tags, including the CRLF.
+pub const MAX_BODY_LENGTH: usize = 512;
+
+/// An IRCv3 message tag, a key and an optional value.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tag(pub String, pub Option<String>);
+
+impl Tag {
+    pub fn new<S: Into<String>>(key: S, value: Option<S>) -> Tag {
+        Tag(key.into(), value.map(|v| v.into()))
+    }
+
+    /// Client-only tags are prefixed with `+` and relayed between clients.
+    pub fn is_client_only(&self) -> bool {
+        self.0.starts_with('+')
+    }
+
+    /// The `batch` tag marking a message as part of the batch `reference`.
+    pub fn batch<S: Into<String>>(reference: S) -> Tag {
+        Tag("batch".to_owned(), Some(reference.into()))
+    }
+}
+
+fn escape_tag_value(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => ret.push_str("\\:"),
+            ' ' => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => ret.push(';'),
+            Some('s') => ret.push(' '),
+            Some('r') => ret.push('\r'),
+            Some('n') => ret.push('\n'),
+            Some(c) => ret.push(c),
+            None => (),
+        }
+    }
+    ret
+}
+
+fn parse_tags(tags: &str) -> Vec<Tag> {
+    tags.split(';')
+        .filter(|t| !t.is_empty())
+        .map(|t| match t.find('=') {
+            Some(i) => Tag(t[..i].to_owned(), Some(unescape_tag_value(&t[i + 1..]))),
+            None => Tag(t.to_owned(), None),
+        })
+        .collect()
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Message {
+    pub tags: Option<Vec<Tag>>,
     pub prefix: Option<Prefix>,
     pub contents: MessageContents,
 }
@@ -45,8 +104,18 @@ impl Message {
         prefix: Option<&str>,
         command: &str,
         args: Vec<&str>,
+    ) -> Result<Message, MessageParseError> {
+        Message::with_tags(None, prefix, command, args)
+    }
+
+    pub fn with_tags(
+        tags: Option<Vec<Tag>>,
+        prefix: Option<&str>,
+        command: &str,
+        args: Vec<&str>,
     ) -> Result<Message, MessageParseError> {
         Ok(Message {
+            tags,
             prefix: prefix.map(|p| p.into()),
             contents: MessageContents::Command(command::Command::new(command, args)?),
         })
@@ -56,20 +125,57 @@ impl Message {
         self.prefix = Some(Prefix::from(pf));
     }
 
-    pub fn to_string(&self) -> String {
+    /// Returns the value of the tag `key`, `Some("")` if the tag is present without a value.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .as_ref()?
+            .iter()
+            .find(|t| t.0 == key)
+            .map(|t| t.1.as_deref().unwrap_or(""))
+    }
+
+    /// Sets a tag, replacing any existing tag with the same key.
+    pub fn set_tag(&mut self, tag: Tag) {
+        let tags = self.tags.get_or_insert_with(Vec::new);
+        match tags.iter_mut().find(|t| t.0 == tag.0) {
+            Some(existing) => *existing = tag,
+            None => tags.push(tag),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut ret = String::new();
+        if let Some(ref tags) = self.tags {
+            if !tags.is_empty() {
+                ret.push('@');
+                for (i, Tag(key, value)) in tags.iter().enumerate() {
+                    if i > 0 {
+                        ret.push(';');
+                    }
+                    ret.push_str(key);
+                    if let Some(value) = value {
+                        ret.push('=');
+                        ret.push_str(&escape_tag_value(value));
+                    }
+                }
+                ret.push(' ');
+            }
+        }
         if let Some(ref prefix) = self.prefix {
-            write!(ret, ":{} ", prefix).unwrap();
+            write!(ret, ":{} ", prefix)?;
         }
         ret.push_str(&self.contents.to_string());
         ret.push_str("\r\n");
-        ret
+        f.write_str(&ret)
     }
 }
 
 impl From<command::Command> for Message {
     fn from(value: command::Command) -> Self {
         Message {
+            tags: None,
             prefix: None,
             contents: MessageContents::Command(value),
         }
@@ -79,6 +185,7 @@ impl From<command::Command> for Message {
 impl From<response::Response> for Message {
     fn from(value: response::Response) -> Self {
         Message {
+            tags: None,
             prefix: None,
             contents: MessageContents::Response(value),
         }
@@ -96,6 +203,21 @@ impl FromStr for Message {
         }
         let mut state = s;
 
+        let tags = if state.starts_with('@') {
+            let tags = state.find(' ').map(|i| &state[1..i]);
+            state = state.find(' ').map_or("", |i| &state[i + 1..]);
+            tags.map(parse_tags)
+        } else {
+            None
+        };
+        // The line has had its CRLF stripped, and the tag section its trailing space.
+        if s.len() - state.len() > MAX_CLIENT_TAGS_LENGTH || state.len() + 2 > MAX_BODY_LENGTH {
+            return Err(ProtocolError::InvalidMessage {
+                string: s.to_owned(),
+                cause: response::Response::ErrInputTooLong("*".to_owned()).into(),
+            });
+        }
+
         let prefix = if state.starts_with(':') {
             let prefix = state.find(' ').map(|i| &state[1..i]);
             state = state.find(' ').map_or("", |i| &state[i + 1..]);
@@ -136,7 +258,7 @@ impl FromStr for Message {
             args.push(suffix);
         }
 
-        Message::new(prefix, command, args).map_err(|e| ProtocolError::InvalidMessage {
+        Message::with_tags(tags, prefix, command, args).map_err(|e| ProtocolError::InvalidMessage {
             string: s.to_owned(),
             cause: e,
         })
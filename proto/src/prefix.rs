@@ -72,9 +72,9 @@ impl fmt::Display for Prefix {
     }
 }
 
-impl Into<Prefix> for String {
-    fn into(self) -> Prefix {
-        Prefix::new_from_str(&self)
+impl From<String> for Prefix {
+    fn from(s: String) -> Self {
+        Prefix::new_from_str(&s)
     }
 }
 
@@ -85,7 +85,7 @@ impl FromStr for Prefix {
     }
 }
 
-impl<'a> From<&'a str> for Prefix {
+impl From<&str> for Prefix {
     fn from(s: &str) -> Self {
         Prefix::new_from_str(s)
     }
@@ -1,29 +1,162 @@
-use std::fmt::format;
+use std::fmt;
 
 #[repr(u32)]
 #[derive(Clone, PartialEq, Debug)]
 pub enum Response {
+    RplWelcome(String, String) = 1,
+    RplISupport(String, Vec<String>) = 5,
+    RplUModeIs(String, String) = 221,
+    /* Nick, Target nick, Fingerprint */
+    RplWhoisCertFp(String, String, String) = 276,
+    RplAway(String, String, String) = 301,
+    RplUnAway(String) = 305,
+    RplNowAway(String) = 306,
+    /* Nick, Target nick, User, Host, Realname */
+    RplWhoisUser(String, String, String, String, String) = 311,
+    /* Nick, Target nick, Server, Server info */
+    RplWhoisServer(String, String, String, String) = 312,
+    RplWhoisOperator(String, String) = 313,
+    RplEndOfWho(String, String) = 315,
+    RplWhoisSpecial(String, String, String) = 320,
+    RplEndOfWhois(String, String) = 318,
+    RplWhoisChannels(String, String, String) = 319,
+    RplChannelModeIs(String, String, String) = 324,
+    RplWhoisAccount(String, String, String) = 330,
+    /* Nick, Invited nick, Channel */
+    RplInviting(String, String, String) = 341,
+    /* Nick, Channel, User, Host, Server, Target nick, Flags, Realname */
+    RplWhoReply(String, String, String, String, String, String, String, String) = 352,
+    /* Nick, Channel symbol, Channel, Names */
+    RplNamReply(String, String, String, String) = 353,
+    RplEndOfNames(String, String) = 366,
+    /* Nick, Channel, Mask, Setter, Set at */
+    RplBanList(String, String, String, String, u64) = 367,
+    RplEndOfBanList(String, String) = 368,
+    /* Nick, Target nick, Host, IP address */
+    RplWhoisHost(String, String, String, String) = 378,
+    RplYoureOper(String) = 381,
+    /* Nick, Config file */
+    RplRehashing(String, String) = 382,
+    RplHostHidden(String, String) = 396,
+    ErrNoSuchNick(String, String) = 401,
+    ErrNoSuchChannel(String, String) = 403,
+    ErrInvalidCapCmd(String) = 410,
+    ErrInputTooLong(String) = 417,
     ErrNoSuchCommand(String) = 421,
     ErrNickCollision(String) = 436,
     ErrNotRegistered = 451,
+    /* Nick, Target nick, Channel */
+    ErrUserNotInChannel(String, String, String) = 441,
+    ErrNotOnChannel(String, String) = 442,
+    ErrUserOnChannel(String, String, String) = 443,
     ErrNeedMoreParams(String) = 461,
+    ErrAlreadyRegistred(String) = 462,
+    ErrPasswdMismatch(String) = 464,
+    ErrChannelIsFull(String, String) = 471,
+    ErrUnknownMode(String, char) = 472,
+    ErrInviteOnlyChan(String, String) = 473,
+    ErrBannedFromChan(String, String) = 474,
+    ErrBadChannelKey(String, String) = 475,
+    /* Nick, Channel, Mask */
+    ErrBanListFull(String, String, String) = 478,
+    ErrNoPrivileges(String) = 481,
+    ErrChanOPrivsNeeded(String, String) = 482,
+    ErrNoOperHost(String) = 491,
+    ErrUModeUnknownFlag(String) = 501,
+    ErrUsersDontMatch(String) = 502,
+    RplWhoisSecure(String, String) = 671,
+    RplMonOnline(String, String) = 730,
+    RplMonOffline(String, String) = 731,
+    RplMonList(String, String) = 732,
+    RplEndOfMonList(String) = 733,
+    /* Nick, Limit, Targets */
+    ErrMonListFull(String, usize, String) = 734,
+    /* Nick, Prefix, Account */
+    RplLoggedIn(String, String, String) = 900,
+    RplSaslSuccess(String) = 903,
+    ErrSaslFail(String) = 904,
+    ErrSaslAborted(String) = 906,
+    ErrSaslAlready(String) = 907,
+    /* Nick, Mechanisms */
+    RplSaslMechs(String, String) = 908,
 }
 
-impl Response {
-    pub fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = match self {
+            Response::RplWelcome(nick, msg) => format!("001 {} :{}", nick, msg),
+            Response::RplISupport(nick, tokens) => format!("005 {} {} :are supported by this server", nick, tokens.join(" ")),
+            Response::RplUModeIs(nick, modes) => format!("221 {} {}", nick, modes),
+            Response::RplWhoisCertFp(nick, target, fp) => format!("276 {} {} :has client certificate fingerprint {}", nick, target, fp),
+            Response::RplAway(nick, target, msg) => format!("301 {} {} :{}", nick, target, msg),
+            Response::RplUnAway(nick) => format!("305 {} :You are no longer marked as being away", nick),
+            Response::RplNowAway(nick) => format!("306 {} :You have been marked as being away", nick),
+            Response::RplWhoisUser(nick, target, user, host, real) => format!("311 {} {} {} {} * :{}", nick, target, user, host, real),
+            Response::RplWhoisServer(nick, target, server, info) => format!("312 {} {} {} :{}", nick, target, server, info),
+            Response::RplWhoisOperator(nick, target) => format!("313 {} {} :is an IRC operator", nick, target),
+            Response::RplEndOfWho(nick, mask) => format!("315 {} {} :End of WHO list", nick, mask),
+            Response::RplWhoisSpecial(nick, target, text) => format!("320 {} {} :{}", nick, target, text),
+            Response::RplEndOfWhois(nick, target) => format!("318 {} {} :End of /WHOIS list", nick, target),
+            Response::RplWhoisChannels(nick, target, chans) => format!("319 {} {} :{}", nick, target, chans),
+            Response::RplChannelModeIs(nick, chan, modes) => format!("324 {} {} {}", nick, chan, modes),
+            Response::RplWhoisAccount(nick, target, account) => format!("330 {} {} {} :is logged in as", nick, target, account),
+            Response::RplInviting(nick, target, chan) => format!("341 {} {} {}", nick, target, chan),
+            Response::RplWhoReply(nick, chan, user, host, server, target, flags, real) => {
+                format!("352 {} {} {} {} {} {} {} :0 {}", nick, chan, user, host, server, target, flags, real)
+            }
+            Response::RplNamReply(nick, symbol, chan, names) => format!("353 {} {} {} :{}", nick, symbol, chan, names),
+            Response::RplEndOfNames(nick, chan) => format!("366 {} {} :End of /NAMES list", nick, chan),
+            Response::RplBanList(nick, chan, mask, setter, time) => format!("367 {} {} {} {} {}", nick, chan, mask, setter, time),
+            Response::RplEndOfBanList(nick, chan) => format!("368 {} {} :End of channel ban list", nick, chan),
+            Response::RplWhoisHost(nick, target, host, ip) => format!("378 {} {} :is connecting from *@{} {}", nick, target, host, ip),
+            Response::RplYoureOper(nick) => format!("381 {} :You are now an IRC operator", nick),
+            Response::RplRehashing(nick, file) => format!("382 {} {} :Rehashing", nick, file),
+            Response::RplHostHidden(nick, host) => format!("396 {} {} :is now your displayed host", nick, host),
+            Response::ErrNoSuchNick(nick, target) => format!("401 {} {} :No such nick/channel", nick, target),
+            Response::ErrNoSuchChannel(nick, chan) => format!("403 {} {} :No such channel", nick, chan),
+            Response::ErrInvalidCapCmd(cmd) => format!("410 * {} :Invalid CAP command", cmd),
+            Response::ErrInputTooLong(nick) => format!("417 {} :Input line was too long", nick),
             Response::ErrNoSuchCommand(cmd) => format!("421 {} :Unknown command", cmd),
             Response::ErrNickCollision(nick) => format!("436 {} :Nickname collision KILL", nick),
             Response::ErrNotRegistered => "451 :You have not registered".to_string(),
-            Response::ErrNeedMoreParams(cmd) => format!("462 {} :Not enough parameters", cmd),
-        }
+            Response::ErrUserNotInChannel(nick, target, chan) => format!("441 {} {} {} :They aren't on that channel", nick, target, chan),
+            Response::ErrNotOnChannel(nick, chan) => format!("442 {} {} :You're not on that channel", nick, chan),
+            Response::ErrUserOnChannel(nick, target, chan) => format!("443 {} {} {} :is already on channel", nick, target, chan),
+            Response::ErrNeedMoreParams(cmd) => format!("461 {} :Not enough parameters", cmd),
+            Response::ErrAlreadyRegistred(nick) => format!("462 {} :Unauthorized command (already registered)", nick),
+            Response::ErrPasswdMismatch(nick) => format!("464 {} :Password incorrect", nick),
+            Response::ErrChannelIsFull(nick, chan) => format!("471 {} {} :Cannot join channel (+l)", nick, chan),
+            Response::ErrUnknownMode(nick, mode) => format!("472 {} {} :is unknown mode char to me", nick, mode),
+            Response::ErrInviteOnlyChan(nick, chan) => format!("473 {} {} :Cannot join channel (+i)", nick, chan),
+            Response::ErrBannedFromChan(nick, chan) => format!("474 {} {} :Cannot join channel (+b)", nick, chan),
+            Response::ErrBadChannelKey(nick, chan) => format!("475 {} {} :Cannot join channel (+k)", nick, chan),
+            Response::ErrBanListFull(nick, chan, mask) => format!("478 {} {} {} :Channel ban list is full", nick, chan, mask),
+            Response::ErrNoPrivileges(nick) => format!("481 {} :Permission Denied- You're not an IRC operator", nick),
+            Response::ErrChanOPrivsNeeded(nick, chan) => format!("482 {} {} :You're not channel operator", nick, chan),
+            Response::ErrNoOperHost(nick) => format!("491 {} :No O-lines for your host", nick),
+            Response::ErrUModeUnknownFlag(nick) => format!("501 {} :Unknown MODE flag", nick),
+            Response::ErrUsersDontMatch(nick) => format!("502 {} :Cant change mode for other users", nick),
+            Response::RplWhoisSecure(nick, target) => format!("671 {} {} :is using a secure connection", nick, target),
+            Response::RplMonOnline(nick, targets) => format!("730 {} :{}", nick, targets),
+            Response::RplMonOffline(nick, targets) => format!("731 {} :{}", nick, targets),
+            Response::RplMonList(nick, targets) => format!("732 {} :{}", nick, targets),
+            Response::RplEndOfMonList(nick) => format!("733 {} :End of MONITOR list", nick),
+            Response::ErrMonListFull(nick, limit, targets) => format!("734 {} {} {} :Monitor list is full", nick, limit, targets),
+            Response::RplLoggedIn(nick, prefix, account) => {
+                format!("900 {} {} {} :You are now logged in as {}", nick, prefix, account, account)
+            }
+            Response::RplSaslSuccess(nick) => format!("903 {} :SASL authentication successful", nick),
+            Response::ErrSaslFail(nick) => format!("904 {} :SASL authentication failed", nick),
+            Response::ErrSaslAborted(nick) => format!("906 {} :SASL authentication aborted", nick),
+            Response::ErrSaslAlready(nick) => format!("907 {} :You have already authenticated using SASL", nick),
+            Response::RplSaslMechs(nick, mechs) => format!("908 {} {} :are available SASL mechanisms", nick, mechs),
+        };
+        f.write_str(&line)
     }
 }
 
 impl<'a> From<&'a Response> for String {
     fn from(value: &'a Response) -> Self {
-        match value {
-            v => v.to_string(),
-        }
+        value.to_string()
     }
 }
@@ -43,19 +43,16 @@ impl Pinger {
     }
 
     fn handle_message(self: Pin<&mut Self>, message: &Message) -> error::Result<()> {
-        match &message.contents {
-            MessageContents::Command(command) => {
-                match command {
-                    Command::PING(ref data, _) => {
-                        self.send_pong(data)?;
-                    }
-                    Command::PONG(_, None) | Command::PONG(_, Some(_)) => {
-                        self.project().ping_deadline.set(None);
-                    }
-                    _ => (),
+        if let MessageContents::Command(command) = &message.contents {
+            match command {
+                Command::PING(ref data, _) => {
+                    self.send_pong(data)?;
                 }
-            },
-            _ => (),
+                Command::PONG(_, None) | Command::PONG(_, Some(_)) => {
+                    self.project().ping_deadline.set(None);
+                }
+                _ => (),
+            }
         }
         Ok(())
     }
@@ -64,17 +61,17 @@ impl Pinger {
         self.project()
             .tx
             .send(Command::Pong(data.to_owned(), None).into())
-            .map_err(|e| ProtocolError::SendError(e))?;
+            .map_err(ProtocolError::from)?;
         Ok(())
     }
 
     fn send_ping(self: Pin<&mut Self>) -> error::Result<()> {
         //FIXME: Send Proper server address.
-        let data = format!("{}", "127.0.0.1");
+        let data = "127.0.0.1".to_string();
         let mut this = self.project();
         this.tx
             .send(Command::Ping(data.clone(), None).into())
-            .map_err(|e| ProtocolError::SendError(e))?;
+            .map_err(ProtocolError::from)?;
         if this.ping_deadline.is_none() {
             let ping_deadline = time::sleep(*this.ping_timeout);
             this.ping_deadline.set(Some(ping_deadline));
@@ -92,10 +89,8 @@ impl Future for Pinger {
                 Poll::Pending => (),
             }
         }
-        if let Poll::Ready(_) = self.as_mut().project().ping_interval.poll_tick(cx) {
-            if *self.as_mut().project().enabled {
-                self.as_mut().send_ping()?;
-            }
+        if self.as_mut().project().ping_interval.poll_tick(cx).is_ready() && *self.as_mut().project().enabled {
+            self.as_mut().send_ping()?;
         }
         Poll::Pending
     }
@@ -117,8 +112,8 @@ where
     pub fn new(inner: Framed<T, MessageCodec>, tx: UnboundedSender<Message>) -> Transport<T> {
         let pinger = Some(Pinger::new(tx));
         Transport {
-            inner: inner,
-            pinger: pinger,
+            inner,
+            pinger,
         }
     }
 
@@ -143,7 +138,7 @@ where
         let result = ready!(self.as_mut().project().inner.poll_next(cx));
         let message = match result {
             None => return Poll::Ready(None),
-            Some(message) => message?,
+            Some(message) => message??,
         };
 
         if let Some(pinger) = self.as_mut().project().pinger.as_pin_mut() {
@@ -0,0 +1,35 @@
+use sha2::{Digest, Sha256, Sha512};
+
+/// Fingerprints of the certificate a client presented during the TLS
+/// handshake, as lowercase hex without separators.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertFp {
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl CertFp {
+    /// Fingerprints a DER-encoded certificate.
+    pub fn new(der: &[u8]) -> CertFp {
+        CertFp {
+            sha256: hex(&Sha256::digest(der)),
+            sha512: hex(&Sha512::digest(der)),
+        }
+    }
+
+    /// Whether `fingerprint` is either of these fingerprints. Case and any
+    /// colons between bytes are ignored, so fingerprints can be copied from
+    /// `openssl x509 -fingerprint` as they are.
+    pub fn matches(&self, fingerprint: &str) -> bool {
+        let fingerprint: String = fingerprint
+            .chars()
+            .filter(|c| *c != ':')
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        fingerprint == self.sha256 || fingerprint == self.sha512
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,470 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use proto::response::Response;
+
+use crate::class::mask_matches;
+use crate::client::Client;
+use crate::server::casefold;
+
+/// Channel membership ranks, highest first. The order here is the order
+/// advertised in the `PREFIX` ISUPPORT token and used when displaying prefixes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Rank {
+    Founder,
+    Protected,
+    Operator,
+    HalfOp,
+    Voice,
+}
+
+impl Rank {
+    pub const ALL: &'static [Rank] = &[
+        Rank::Founder,
+        Rank::Protected,
+        Rank::Operator,
+        Rank::HalfOp,
+        Rank::Voice,
+    ];
+
+    pub fn mode(&self) -> char {
+        match self {
+            Rank::Founder => 'q',
+            Rank::Protected => 'a',
+            Rank::Operator => 'o',
+            Rank::HalfOp => 'h',
+            Rank::Voice => 'v',
+        }
+    }
+
+    pub fn prefix(&self) -> char {
+        match self {
+            Rank::Founder => '~',
+            Rank::Protected => '&',
+            Rank::Operator => '@',
+            Rank::HalfOp => '%',
+            Rank::Voice => '+',
+        }
+    }
+
+    /// The `PREFIX` ISUPPORT token, e.g. `PREFIX=(qaohv)~&@%+`.
+    pub fn isupport() -> String {
+        let modes: String = Rank::ALL.iter().map(|r| r.mode()).collect();
+        let prefixes: String = Rank::ALL.iter().map(|r| r.prefix()).collect();
+        format!("PREFIX=({}){}", modes, prefixes)
+    }
+
+    /// Formats a member's prefixes: all of them with `multi-prefix`, otherwise
+    /// only the highest.
+    pub fn prefixes(ranks: &BTreeSet<Rank>, multi: bool) -> String {
+        if multi {
+            ranks.iter().map(|r| r.prefix()).collect()
+        } else {
+            ranks.iter().next().map(|r| r.prefix().to_string()).unwrap_or_default()
+        }
+    }
+}
+
+/// An entry in a channel's ban list.
+#[derive(Debug, Clone)]
+pub struct Ban {
+    /// A `nick!user@host` mask, or `~S:` and a certificate fingerprint.
+    pub mask: String,
+    pub setter: String,
+    /// Seconds since the Unix epoch.
+    pub set_at: u64,
+}
+
+impl Ban {
+    /// The extban prefix matching a client certificate's fingerprint.
+    const CERTFP: &'static str = "~S:";
+
+    /// Fills in the missing parts of a ban mask, so `nick` becomes
+    /// `nick!*@*` and `user@host` becomes `*!user@host`. Fingerprints lose
+    /// their colons and case.
+    pub fn normalize(mask: &str) -> String {
+        if let Some(fp) = mask.strip_prefix(Ban::CERTFP) {
+            let fp: String = fp.chars().filter(|c| *c != ':').collect();
+            return format!("{}{}", Ban::CERTFP, fp.to_ascii_lowercase());
+        }
+        let (nick, rest) = match mask.split_once('!') {
+            Some((nick, rest)) => (nick, rest),
+            None if mask.contains('@') => ("*", mask),
+            None => (mask, "*"),
+        };
+        let (user, host) = rest.split_once('@').unwrap_or((rest, "*"));
+        let or_any = |part: &str| if part.is_empty() { "*".to_owned() } else { part.to_owned() };
+        format!("{}!{}@{}", or_any(nick), or_any(user), or_any(host))
+    }
+
+    /// Whether the ban covers `client`, by its displayed host, real host or
+    /// IP address, or by its certificate.
+    pub fn matches(&self, client: &Client) -> bool {
+        let state = client.state();
+        if let Some(fp) = self.mask.strip_prefix(Ban::CERTFP) {
+            return state.certfp().is_some_and(|certfp| certfp.matches(fp));
+        }
+        let hosts = [client.host(), client.real_host(), client.address().ip().to_string()];
+        hosts
+            .iter()
+            .any(|host| mask_matches(&self.mask, &format!("{}!{}@{}", state.nick(), state.user(), host)))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChannelModes {
+    pub invite_only: bool,
+    pub key: Option<String>,
+    pub limit: Option<usize>,
+    pub bans: Vec<Ban>,
+}
+
+impl ChannelModes {
+    /// The `CHANMODES` ISUPPORT token for the modes above.
+    pub const ISUPPORT: &'static str = "CHANMODES=b,k,l,i";
+
+    /// The `EXTBAN` ISUPPORT token: `~S:<fingerprint>` bans a certificate.
+    pub const EXTBAN: &'static str = "EXTBAN=~,S";
+
+    /// The most bans a channel may have.
+    pub const MAX_BANS: usize = 100;
+}
+
+/// Formats the modes for RPL_CHANNELMODEIS, e.g. `+ikl secret 10`.
+impl fmt::Display for ChannelModes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut modes = "+".to_owned();
+        let mut params = Vec::new();
+        if self.invite_only {
+            modes.push('i');
+        }
+        if let Some(ref key) = self.key {
+            modes.push('k');
+            params.push(key.clone());
+        }
+        if let Some(limit) = self.limit {
+            modes.push('l');
+            params.push(limit.to_string());
+        }
+        params.insert(0, modes);
+        f.write_str(&params.join(" "))
+    }
+}
+
+/// The outcome of applying a mode string to a channel.
+#[derive(Debug, Default)]
+pub struct ModeChange {
+    /// The modes which were applied, e.g. `+o-i`, empty if none were.
+    pub modes: String,
+    pub params: Vec<String>,
+    pub errors: Vec<Response>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    name: String,
+    members: HashMap<String, BTreeSet<Rank>>,
+    modes: ChannelModes,
+    /* Casefolded nicks invited since they last joined */
+    invites: HashSet<String>,
+}
+
+impl Channel {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            members: HashMap::new(),
+            modes: ChannelModes::default(),
+            invites: HashSet::new(),
+        }
+    }
+
+    /// Channel names start with `#` and may not contain spaces, commas or BELs.
+    pub fn valid_name(name: &str) -> bool {
+        name.starts_with('#')
+            && name.len() > 1
+            && !name.contains([' ', ',', '\x07'])
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_member(&self, nick: &str) -> bool {
+        self.members.contains_key(nick)
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &String> {
+        self.members.keys()
+    }
+
+    /// The member's nick as it appears in the channel, matched case-insensitively.
+    pub fn member_name(&self, nick: &str) -> Option<&String> {
+        let folded = casefold(nick);
+        self.members.keys().find(|n| casefold(n) == folded)
+    }
+
+    pub fn ranks(&self, nick: &str) -> Option<&BTreeSet<Rank>> {
+        self.members.get(nick)
+    }
+
+    /// The highest rank `nick` holds, if any.
+    pub fn highest_rank(&self, nick: &str) -> Option<Rank> {
+        self.members.get(nick).and_then(|r| r.iter().next()).copied()
+    }
+
+    /// Whether `nick` holds `rank` or any rank above it.
+    pub fn has_rank(&self, nick: &str, rank: Rank) -> bool {
+        self.highest_rank(nick).is_some_and(|highest| highest <= rank)
+    }
+
+    /// Whether `nick` holds a rank above every rank `target` holds.
+    pub fn outranks(&self, nick: &str, target: &str) -> bool {
+        match (self.highest_rank(nick), self.highest_rank(target)) {
+            (Some(rank), Some(target)) => rank < target,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns false if `nick` is not a member.
+    pub fn set_rank(&mut self, nick: &str, rank: Rank, enabled: bool) -> bool {
+        match self.members.get_mut(nick) {
+            Some(ranks) if enabled => {
+                ranks.insert(rank);
+                true
+            }
+            Some(ranks) => {
+                ranks.remove(&rank);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn modes(&self) -> &ChannelModes {
+        &self.modes
+    }
+
+    /// Applies a mode string set by `setter`, who must already be known to
+    /// be an operator. Ranks above the setter's own can't be given or taken.
+    /// Parameterised modes without a parameter are skipped.
+    pub fn apply_modes(&mut self, setter: &str, modes: &str, params: &[String]) -> ModeChange {
+        let mut change = ModeChange::default();
+        let mut params = params.iter();
+        let mut adding = true;
+        let mut sign = None;
+        for c in modes.chars() {
+            let param = match c {
+                '+' | '-' => {
+                    adding = c == '+';
+                    continue;
+                }
+                'i' if self.modes.invite_only == adding => continue,
+                'i' => {
+                    self.modes.invite_only = adding;
+                    None
+                }
+                'k' if adding => match params.next() {
+                    Some(key) => {
+                        self.modes.key = Some(key.clone());
+                        Some(key.clone())
+                    }
+                    None => continue,
+                },
+                'k' => {
+                    params.next();
+                    match self.modes.key.take() {
+                        Some(_) => Some("*".to_owned()),
+                        None => continue,
+                    }
+                }
+                'l' if adding => match params.next().and_then(|p| p.parse::<usize>().ok()) {
+                    Some(limit) => {
+                        self.modes.limit = Some(limit);
+                        Some(limit.to_string())
+                    }
+                    None => continue,
+                },
+                'l' => match self.modes.limit.take() {
+                    Some(_) => None,
+                    None => continue,
+                },
+                'b' => {
+                    let mask = match params.next() {
+                        Some(mask) => Ban::normalize(mask),
+                        None => continue,
+                    };
+                    let existing = self.modes.bans.iter().position(|b| casefold(&b.mask) == casefold(&mask));
+                    match existing {
+                        Some(_) if adding => continue,
+                        Some(i) => Some(self.modes.bans.remove(i).mask),
+                        None if !adding => continue,
+                        None if self.modes.bans.len() >= ChannelModes::MAX_BANS => {
+                            change.errors.push(Response::ErrBanListFull(
+                                setter.to_owned(),
+                                self.name.clone(),
+                                mask,
+                            ));
+                            continue;
+                        }
+                        None => {
+                            let set_at = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map_or(0, |d| d.as_secs());
+                            self.modes.bans.push(Ban { mask: mask.clone(), setter: setter.to_owned(), set_at });
+                            Some(mask)
+                        }
+                    }
+                }
+                c => match Rank::ALL.iter().find(|r| r.mode() == c) {
+                    Some(rank) => {
+                        let target = match params.next() {
+                            Some(target) => target,
+                            None => continue,
+                        };
+                        if !self.has_rank(setter, *rank) {
+                            change.errors.push(Response::ErrChanOPrivsNeeded(setter.to_owned(), self.name.clone()));
+                            continue;
+                        }
+                        match self.member_name(target).cloned() {
+                            Some(member) => {
+                                self.set_rank(&member, *rank, adding);
+                                Some(member)
+                            }
+                            None => {
+                                change.errors.push(Response::ErrUserNotInChannel(
+                                    setter.to_owned(),
+                                    target.clone(),
+                                    self.name.clone(),
+                                ));
+                                continue;
+                            }
+                        }
+                    }
+                    None => {
+                        change.errors.push(Response::ErrUnknownMode(setter.to_owned(), c));
+                        continue;
+                    }
+                },
+            };
+            if sign != Some(adding) {
+                change.modes.push(if adding { '+' } else { '-' });
+                sign = Some(adding);
+            }
+            change.modes.push(c);
+            change.params.extend(param);
+        }
+        change
+    }
+
+    pub fn invite(&mut self, nick: &str) {
+        self.invites.insert(casefold(nick));
+    }
+
+    pub fn is_invited(&self, nick: &str) -> bool {
+        self.invites.contains(&casefold(nick))
+    }
+
+    /// Returns why `client` may not join with `key`, if it may not. An
+    /// invite bypasses every check.
+    pub fn refusal(&self, client: &Client, key: Option<&str>) -> Option<Response> {
+        let state = client.state();
+        let nick = state.nick();
+        if self.is_invited(nick) {
+            return None;
+        }
+        if self.modes.bans.iter().any(|ban| ban.matches(client)) {
+            return Some(Response::ErrBannedFromChan(nick.to_owned(), self.name.clone()));
+        }
+        if self.modes.invite_only {
+            return Some(Response::ErrInviteOnlyChan(nick.to_owned(), self.name.clone()));
+        }
+        if self.modes.key.is_some() && self.modes.key.as_deref() != key {
+            return Some(Response::ErrBadChannelKey(nick.to_owned(), self.name.clone()));
+        }
+        if self.modes.limit.is_some_and(|limit| self.members.len() >= limit) {
+            return Some(Response::ErrChannelIsFull(nick.to_owned(), self.name.clone()));
+        }
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Returns false if `nick` was already a member. The first member of a
+    /// channel becomes its operator. Joining uses up any invite.
+    pub fn join(&mut self, nick: &str) -> bool {
+        if self.members.contains_key(nick) {
+            return false;
+        }
+        self.invites.remove(&casefold(nick));
+        let mut ranks = BTreeSet::new();
+        if self.members.is_empty() {
+            ranks.insert(Rank::Operator);
+        }
+        self.members.insert(nick.to_owned(), ranks);
+        true
+    }
+
+    pub fn rename_member(&mut self, old: &str, new: &str) {
+        if let Some(ranks) = self.members.remove(old) {
+            self.members.insert(new.to_owned(), ranks);
+        }
+    }
+
+    /// Returns false if `nick` was not a member.
+    pub fn part(&mut self, nick: &str) -> bool {
+        self.members.remove(nick).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A channel `a` created, with `b` joined as an operator and `c` as a member.
+    fn channel() -> Channel {
+        let mut channel = Channel::new("#test");
+        channel.join("a");
+        channel.join("b");
+        channel.join("c");
+        channel.set_rank("b", Rank::Operator, true);
+        channel
+    }
+
+    #[test]
+    fn grants_ranks_up_to_own() {
+        let mut channel = channel();
+        let change = channel.apply_modes("b", "+hv", &["c".to_owned(), "c".to_owned()]);
+        assert_eq!(change.modes, "+hv");
+        assert!(change.errors.is_empty());
+        assert!(channel.has_rank("c", Rank::HalfOp));
+    }
+
+    #[test]
+    fn refuses_ranks_above_own() {
+        let mut channel = channel();
+        let change = channel.apply_modes("b", "+qa", &["c".to_owned(), "c".to_owned()]);
+        assert_eq!(change.modes, "");
+        assert_eq!(change.errors.len(), 2);
+        assert!(matches!(change.errors[0], Response::ErrChanOPrivsNeeded(..)));
+        assert_eq!(channel.highest_rank("c"), None);
+
+        channel.set_rank("a", Rank::Founder, true);
+        let change = channel.apply_modes("b", "-q", &["a".to_owned()]);
+        assert_eq!(change.modes, "");
+        assert!(channel.has_rank("a", Rank::Founder));
+    }
+
+    #[test]
+    fn outranks_only_lower_ranks() {
+        let channel = channel();
+        assert!(channel.outranks("a", "c"));
+        assert!(!channel.outranks("a", "b"));
+        assert!(!channel.outranks("c", "a"));
+        assert!(!channel.outranks("c", "c"));
+    }
+}
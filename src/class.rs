@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use ipnet::IpNet;
+
+use crate::config::{Allow, Class};
+
+/// What a connection is matched against `[[allow]]` blocks by.
+#[derive(Debug)]
+pub struct ConnectionInfo<'a> {
+    pub ip: IpAddr,
+    /// The verified hostname, or empty if there isn't one.
+    pub host: &'a str,
+    pub listener: &'a str,
+    pub tls: bool,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    per_class: HashMap<String, usize>,
+    per_ip: HashMap<(String, IpAddr), usize>,
+    per_cidr: HashMap<(String, IpNet), usize>,
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Assigns connections to classes and enforces the classes' connection limits.
+#[derive(Debug, Clone)]
+pub struct Classes {
+    classes: HashMap<String, Arc<Class>>,
+    allow: Vec<Allow>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Classes {
+    pub fn new(classes: &[Class], allow: &[Allow]) -> Classes {
+        let mut classes: HashMap<String, Arc<Class>> = classes
+            .iter()
+            .map(|c| (c.name.clone(), Arc::new(c.clone())))
+            .collect();
+        classes
+            .entry(Class::DEFAULT.to_owned())
+            .or_insert_with(|| Arc::new(Class::fallback()));
+        Classes {
+            classes,
+            allow: allow.to_vec(),
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Classes with new settings, which keep counting the connections
+    /// already admitted. Those keep the settings of the class they were
+    /// admitted to.
+    pub fn reconfigured(&self, classes: &[Class], allow: &[Allow]) -> Classes {
+        Classes {
+            counts: self.counts.clone(),
+            ..Classes::new(classes, allow)
+        }
+    }
+
+    /// The class of the first `[[allow]]` block matching `info`. Without any
+    /// `[[allow]]` blocks everyone is in the default class.
+    fn find(&self, info: &ConnectionInfo) -> Option<Arc<Class>> {
+        if self.allow.is_empty() {
+            return self.classes.get(Class::DEFAULT).cloned();
+        }
+        self.allow
+            .iter()
+            .find(|allow| {
+                allow.ip.is_none_or(|net| net.contains(&info.ip))
+                    && allow
+                        .host
+                        .as_ref()
+                        .is_none_or(|mask| !info.host.is_empty() && mask_matches(mask, info.host))
+                    && allow.listener.as_ref().is_none_or(|l| l == info.listener)
+                    && allow.tls.is_none_or(|tls| tls == info.tls)
+            })
+            .and_then(|allow| self.classes.get(&allow.class).cloned())
+    }
+
+    /// Puts a connection in its class, or returns why it may not connect.
+    /// The connection counts towards the class's limits until the returned
+    /// guard is dropped.
+    pub fn admit(&self, info: &ConnectionInfo) -> Result<ClassGuard, String> {
+        let class = self
+            .find(info)
+            .ok_or_else(|| "You are not authorised to use this server".to_owned())?;
+        let ip = info.ip.to_canonical();
+        let prefix = match ip {
+            IpAddr::V4(_) => class.cidr_v4,
+            IpAddr::V6(_) => class.cidr_v6,
+        };
+        let cidr = IpNet::new(ip, prefix)
+            .map(|net| net.trunc())
+            .unwrap_or_else(|_| IpNet::from(ip));
+        let ip_key = (class.name.clone(), ip);
+        let cidr_key = (class.name.clone(), cidr);
+
+        let mut counts = self.counts.lock().expect("class counts poisoned");
+        let over = |count: Option<&usize>, limit: Option<usize>| {
+            limit.is_some_and(|limit| count.copied().unwrap_or(0) >= limit)
+        };
+        if over(counts.per_class.get(&class.name), class.max_clients) {
+            return Err("Too many connections in your class".to_owned());
+        }
+        if over(counts.per_ip.get(&ip_key), class.max_per_ip) {
+            return Err("Too many connections from your address".to_owned());
+        }
+        if over(counts.per_cidr.get(&cidr_key), class.max_per_cidr) {
+            return Err("Too many connections from your network".to_owned());
+        }
+        *counts.per_class.entry(class.name.clone()).or_insert(0) += 1;
+        *counts.per_ip.entry(ip_key.clone()).or_insert(0) += 1;
+        *counts.per_cidr.entry(cidr_key.clone()).or_insert(0) += 1;
+        drop(counts);
+
+        Ok(ClassGuard {
+            class,
+            ip_key,
+            cidr_key,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+/// A connection's place in its class, released when dropped.
+#[derive(Debug)]
+pub struct ClassGuard {
+    class: Arc<Class>,
+    ip_key: (String, IpAddr),
+    cidr_key: (String, IpNet),
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ClassGuard {
+    pub fn class(&self) -> &Class {
+        &self.class
+    }
+}
+
+impl Drop for ClassGuard {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            decrement(&mut counts.per_class, &self.class.name);
+            decrement(&mut counts.per_ip, &self.ip_key);
+            decrement(&mut counts.per_cidr, &self.cidr_key);
+        }
+    }
+}
+
+/// Matches `text` against a case-insensitive mask where `*` matches any run
+/// of characters and `?` any single character.
+pub fn mask_matches(mask: &str, text: &str) -> bool {
+    let mask: Vec<char> = mask.to_ascii_lowercase().chars().collect();
+    let text: Vec<char> = text.to_ascii_lowercase().chars().collect();
+    let (mut m, mut t) = (0, 0);
+    // The position after the last `*` seen, and where in the text it matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            star = Some((m + 1, t));
+            m += 1;
+        } else if let Some((after, matched)) = star {
+            m = after;
+            t = matched + 1;
+            star = Some((after, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
@@ -1,45 +1,37 @@
+use std::collections::{BTreeSet, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::Context;
 
-use crate::tls_socket::Socket;
+use crate::class::ClassGuard;
+use crate::config::Privilege;
+use crate::certfp::CertFp;
+use crate::server::Accepted;
+use crate::tls_socket::{NetStream, Socket};
 use futures_util::future::FusedFuture;
 use futures_util::stream::{FusedStream, SplitSink, SplitStream};
 use futures_util::Sink;
 use futures_util::Stream;
 use futures_util::{Future, StreamExt};
+use proto::caps::Capability;
 use proto::codecs::MessageCodec;
+use proto::command::Command;
 use proto::error::{self, ProtocolError, Result};
-use proto::message::Message;
+use proto::message::{Message, Tag};
+use proto::prefix::Prefix;
 use proto::transport::Transport;
 use std::pin::Pin;
 use std::task::{ready, Poll};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio_util::codec::Framed;
-use tokio::sync::RwLock;
 
 #[derive(Debug)]
 pub struct ClientStream {
-    stream: SplitStream<Transport<Socket<TcpStream>>>,
+    stream: SplitStream<Transport<Socket<NetStream>>>,
     outgoing: Option<Outgoing>,
 }
 
-impl ClientStream {
-    pub async fn collect(mut self) -> error::Result<Vec<Message>> {
-        let mut output = Vec::new();
-        while let Some(msg) = self.next().await {
-            match msg {
-                Ok(m) => output.push(m),
-                Err(e) => return Err(e),
-            }
-        }
-
-        Ok(output)
-    }
-
-}
-
 impl FusedStream for ClientStream {
     fn is_terminated(&self) -> bool {
         false
@@ -68,31 +60,197 @@ impl Stream for ClientStream {
         match ready!(Pin::new(&mut self.as_mut().stream).poll_next(cx)) {
             Some(Ok(msg)) => {
                 //self.state.handle_message(&msg)?;
-                return Poll::Ready(Some(Ok(msg)));
+                Poll::Ready(Some(Ok(msg)))
             }
             other => Poll::Ready(other),
         }
     }
 }
 
+/// Tracks how many bytes are waiting to be written to a client.
+#[derive(Debug, Default)]
+struct SendQueue {
+    queued: AtomicUsize,
+    /* Zero until the client is assigned a class */
+    limit: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+impl SendQueue {
+    fn dequeued(&self, len: usize) {
+        let _ = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |q| Some(q.saturating_sub(len)));
+    }
+}
+
+/// A message waiting to be written, with its length on the wire.
+#[derive(Debug)]
+struct Queued {
+    msg: Message,
+    len: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Sender {
-    tx: UnboundedSender<Message>,
+    tx: UnboundedSender<Queued>,
+    batch_id: Arc<AtomicUsize>,
+    queue: Arc<SendQueue>,
 }
 
 impl Sender {
+    /// Queues a message for the client. If that would take the client over
+    /// its sendq, the connection is closed instead.
     pub fn send<M: Into<Message>>(&self, msg: M) -> error::Result<()> {
+        let msg = msg.into();
+        // The line as the codec writes it, CRLF included.
+        let len = msg.to_string().len();
+        let queued = self.queue.queued.fetch_add(len, Ordering::Relaxed) + len;
+        let limit = self.queue.limit.load(Ordering::Relaxed);
+        if limit != 0 && queued > limit {
+            self.queue.exceeded.store(true, Ordering::Relaxed);
+            // Still wake the writer so it notices and closes the connection.
+            let _ = self.tx.send(Queued { msg, len });
+            return Err(ProtocolError::SendQExceeded);
+        }
         self.tx
-            .send(msg.into())
-            .map_err(|e| ProtocolError::SendError(e))
+            .send(Queued { msg, len })
+            .map_err(|e| mpsc::error::SendError(e.0.msg).into())
+    }
+
+    /// Opens a batch of type `kind`, sending the `BATCH +reference` line.
+    /// The reference is unique for the lifetime of the connection.
+    #[allow(dead_code)]
+    pub fn batch<S: Into<String>>(
+        &self,
+        prefix: Option<Prefix>,
+        kind: S,
+        params: Vec<String>,
+    ) -> error::Result<Batch> {
+        self.batch_tagged(prefix, Vec::new(), kind, params)
+    }
+
+    /// Opens a batch whose `BATCH +reference` line carries `tags`.
+    pub fn batch_tagged<S: Into<String>>(
+        &self,
+        prefix: Option<Prefix>,
+        tags: Vec<Tag>,
+        kind: S,
+        params: Vec<String>,
+    ) -> error::Result<Batch> {
+        Batch::open(self.clone(), prefix, tags, None, kind.into(), params)
+    }
+
+    /// A batch which sends its messages untagged and no `BATCH` lines,
+    /// for clients which have not negotiated the `batch` capability.
+    pub fn unbatched(&self, prefix: Option<Prefix>) -> Batch {
+        Batch {
+            sender: self.clone(),
+            prefix,
+            reference: None,
+            parent: None,
+        }
+    }
+
+    fn next_batch_reference(&self) -> String {
+        format!("{:x}", self.batch_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+#[derive(Debug)]
+pub struct Batch {
+    sender: Sender,
+    prefix: Option<Prefix>,
+    reference: Option<String>,
+    parent: Option<String>,
+}
+
+impl Batch {
+    fn open(
+        sender: Sender,
+        prefix: Option<Prefix>,
+        tags: Vec<Tag>,
+        parent: Option<String>,
+        kind: String,
+        params: Vec<String>,
+    ) -> error::Result<Batch> {
+        let reference = sender.next_batch_reference();
+        let params = if params.is_empty() { None } else { Some(params) };
+        let mut msg: Message =
+            Command::BATCH(format!("+{}", reference), Some(kind), params).into();
+        msg.prefix = prefix.clone();
+        for tag in tags {
+            msg.set_tag(tag);
+        }
+        if let Some(ref parent) = parent {
+            msg.set_tag(Tag::batch(parent.as_str()));
+        }
+        sender.send(msg)?;
+        Ok(Batch {
+            sender,
+            prefix,
+            reference: Some(reference),
+            parent,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// Sends a message as part of this batch. Messages without a prefix
+    /// inherit the batch's prefix.
+    pub fn send<M: Into<Message>>(&self, msg: M) -> error::Result<()> {
+        let mut msg: Message = msg.into();
+        if msg.prefix.is_none() {
+            msg.prefix = self.prefix.clone();
+        }
+        if let Some(ref reference) = self.reference {
+            msg.set_tag(Tag::batch(reference.as_str()));
+        }
+        self.sender.send(msg)
+    }
+
+    /// Opens a batch nested inside this one.
+    #[allow(dead_code)]
+    pub fn nested<S: Into<String>>(&self, kind: S, params: Vec<String>) -> error::Result<Batch> {
+        match self.reference {
+            Some(ref reference) => Batch::open(
+                self.sender.clone(),
+                self.prefix.clone(),
+                Vec::new(),
+                Some(reference.clone()),
+                kind.into(),
+                params,
+            ),
+            None => Ok(self.sender.unbatched(self.prefix.clone())),
+        }
+    }
+
+    /// Closes the batch, sending the `BATCH -reference` line.
+    pub fn end(self) -> error::Result<()> {
+        let reference = match self.reference {
+            Some(reference) => reference,
+            None => return Ok(()),
+        };
+        let mut msg: Message = Command::BATCH(format!("-{}", reference), None, None).into();
+        msg.prefix = self.prefix;
+        if let Some(parent) = self.parent {
+            msg.set_tag(Tag::batch(parent));
+        }
+        self.sender.send(msg)
     }
 }
 
 #[derive(Debug)]
 pub struct Outgoing {
-    sink: SplitSink<Transport<Socket<TcpStream>>, Message>,
-    stream: UnboundedReceiver<Message>,
+    sink: SplitSink<Transport<Socket<NetStream>>, Message>,
+    stream: UnboundedReceiver<Queued>,
+    /* Replies the transport makes itself, like PONGs */
+    control: UnboundedReceiver<Message>,
     buffered: Option<Message>,
+    queue: Arc<SendQueue>,
 }
 
 impl Outgoing {
@@ -123,33 +281,116 @@ impl Future for Outgoing {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = &mut *self;
+        if this.queue.exceeded.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(ProtocolError::SendQExceeded));
+        }
         if let Some(msg) = this.buffered.take() {
             ready!(this.try_start_send(cx, msg))?
         }
 
         loop {
-            match this.stream.poll_recv(cx) {
-                Poll::Ready(Some(message)) => ready!(this.try_start_send(cx, message))?,
-                Poll::Ready(None) => {
-                    ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
-                    return Poll::Ready(Ok(()));
-                }
-                Poll::Pending => {
-                    ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
-                    return Poll::Ready(Ok(()));
-                }
-            }
+            // The transport's own replies are too small to count against the sendq.
+            let message = match this.control.poll_recv(cx) {
+                Poll::Ready(Some(message)) => message,
+                _ => match this.stream.poll_recv(cx) {
+                    Poll::Ready(Some(Queued { msg, len })) => {
+                        this.queue.dequeued(len);
+                        msg
+                    }
+                    Poll::Ready(None) | Poll::Pending => {
+                        ready!(Pin::new(&mut this.sink).poll_flush(cx))?;
+                        return Poll::Ready(Ok(()));
+                    }
+                },
+            };
+            ready!(this.try_start_send(cx, message))?
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum UserMode {
+    Invisible,
+    Wallops,
+    Oper,
+    ServerNotices,
+    Registered,
+    Secure,
+    Bot,
+    Cloaked,
+}
+
+impl UserMode {
+    pub const ALL: &'static [UserMode] = &[
+        UserMode::Invisible,
+        UserMode::Wallops,
+        UserMode::Oper,
+        UserMode::ServerNotices,
+        UserMode::Registered,
+        UserMode::Secure,
+        UserMode::Bot,
+        UserMode::Cloaked,
+    ];
+
+    pub fn mode(&self) -> char {
+        match self {
+            UserMode::Invisible => 'i',
+            UserMode::Wallops => 'w',
+            UserMode::Oper => 'o',
+            UserMode::ServerNotices => 's',
+            UserMode::Registered => 'r',
+            UserMode::Secure => 'Z',
+            UserMode::Bot => 'B',
+            UserMode::Cloaked => 'x',
         }
     }
+
+    pub fn from_char(c: char) -> Option<UserMode> {
+        UserMode::ALL.iter().find(|m| m.mode() == c).copied()
+    }
+
+    /// Whether a user may set this mode on themselves. Server notices also
+    /// need the user to be an operator and cloaking needs cloak keys to be
+    /// configured; the rest are only set by the server.
+    pub fn user_settable(&self) -> bool {
+        matches!(
+            self,
+            UserMode::Invisible | UserMode::Wallops | UserMode::Bot | UserMode::ServerNotices | UserMode::Cloaked
+        )
+    }
+
+    /// Whether a user may remove this mode from themselves.
+    pub fn user_removable(&self) -> bool {
+        self.user_settable() || *self == UserMode::Oper
+    }
+
+    /// Formats a set of modes for RPL_UMODEIS, e.g. `+iZ`.
+    pub fn to_string(modes: &BTreeSet<UserMode>) -> String {
+        std::iter::once('+').chain(modes.iter().map(|m| m.mode())).collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientState {
     registered: bool,
     nick: String,
-    user: String
+    user: String,
     realname: String,
-    hostname: String
+    hostname: String,
+    account: Option<String>,
+    away: Option<String>,
+    caps: HashSet<Capability>,
+    /* Casefolded nicks this client is monitoring */
+    monitoring: HashSet<String>,
+    modes: BTreeSet<UserMode>,
+    /* Displayed in place of the real host while +x is set */
+    cloak: Option<String>,
+    /* Username reported by the client's identd */
+    ident: Option<String>,
+    /* Reasons of the DNS blocklists marking this client */
+    listings: Vec<String>,
+    privileges: HashSet<Privilege>,
+    certfp: Option<CertFp>,
 }
 
 impl ClientState {
@@ -159,9 +400,69 @@ impl ClientState {
             nick: String::new(),
             user: String::new(),
             realname:String::new(),
-            hostname: String::new()
+            hostname: String::new(),
+            account: None,
+            away: None,
+            caps: HashSet::new(),
+            monitoring: HashSet::new(),
+            modes: BTreeSet::new(),
+            cloak: None,
+            ident: None,
+            listings: Vec::new(),
+            privileges: HashSet::new(),
+            certfp: None,
         }
     }
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+    pub fn realname(&self) -> &str {
+        &self.realname
+    }
+    /// The resolved hostname, which is empty if the lookup failed.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+    pub fn cloak(&self) -> Option<&str> {
+        self.cloak.as_deref()
+    }
+    pub fn ident(&self) -> Option<&str> {
+        self.ident.as_deref()
+    }
+    pub fn listings(&self) -> &[String] {
+        &self.listings
+    }
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.privileges.contains(&privilege)
+    }
+    /// Fingerprints of the client's TLS certificate, if it sent one.
+    pub fn certfp(&self) -> Option<&CertFp> {
+        self.certfp.as_ref()
+    }
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+    pub fn away(&self) -> Option<&str> {
+        self.away.as_deref()
+    }
+    pub fn monitoring(&self) -> &HashSet<String> {
+        &self.monitoring
+    }
+    pub fn modes(&self) -> &BTreeSet<UserMode> {
+        &self.modes
+    }
+    pub fn has_mode(&self, mode: UserMode) -> bool {
+        self.modes.contains(&mode)
+    }
+    pub fn has_cap(&self, cap: Capability) -> bool {
+        self.caps.contains(&cap)
+    }
+    pub fn caps(&self) -> impl Iterator<Item = &Capability> {
+        self.caps.iter()
+    }
     fn register(&mut self, nick: &String, user: &String, realname: &String) {
         self.nick = nick.to_owned();
         self.user = user.to_owned();
@@ -171,55 +472,200 @@ impl ClientState {
     fn set_hostname(&mut self, hostname: String) {
         self.hostname = hostname;
     }
+    fn set_nick(&mut self, nick: String) {
+        self.nick = nick;
+    }
+    fn set_realname(&mut self, realname: String) {
+        self.realname = realname;
+    }
+    fn set_account(&mut self, account: Option<String>) {
+        self.account = account;
+    }
+    fn set_away(&mut self, away: Option<String>) {
+        self.away = away;
+    }
+    fn set_mode(&mut self, mode: UserMode, enabled: bool) -> bool {
+        if enabled {
+            self.modes.insert(mode)
+        } else {
+            self.modes.remove(&mode)
+        }
+    }
+    fn set_cap(&mut self, cap: Capability, enabled: bool) {
+        if enabled {
+            self.caps.insert(cap);
+        } else {
+            self.caps.remove(&cap);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Client {
-    incoming: Option<SplitStream<Transport<Socket<TcpStream>>>>,
+    incoming: Option<SplitStream<Transport<Socket<NetStream>>>>,
     outgoing: Option<Outgoing>,
     sender: Sender,
     addr: SocketAddr,
+    local_addr: SocketAddr,
+    /* Name of the listener the client connected to */
+    listener: String,
+    class: Option<ClassGuard>,
+    /* Whether a SASL exchange is waiting for the client's response */
+    authenticating: bool,
     state: Arc<ClientState>
 }
 
 impl Client {
-    pub async fn new(sock: Socket<TcpStream>) -> error::Result<Client> {
+    pub async fn new(conn: Accepted) -> error::Result<Client> {
         let (tx_outgoing, rx_outgoing) = mpsc::unbounded_channel();
-        let addr = match &sock {
-            Socket::Plain(s) => s.peer_addr(),
-            Socket::Tls(t) => t.get_ref().get_ref().get_ref().peer_addr(),
-        }.expect("Socket has no peer address");
+        let (tx_control, rx_control) = mpsc::unbounded_channel();
+        let Accepted { socket: sock, listener, peer: addr, local: local_addr, hostname, .. } = conn;
+
+        let mut state = ClientState::new();
+        if let Some(hostname) = hostname {
+            state.set_hostname(hostname);
+        }
+        if sock.is_tls() {
+            state.set_mode(UserMode::Secure, true);
+        }
+        state.certfp = sock.peer_certificate().map(|der| CertFp::new(&der));
 
         let framed = Framed::new(
             sock,
             MessageCodec::new("utf-8").expect("Failed to create message codec"),
         );
-        let conn = Transport::new(framed, tx_outgoing.clone());
+        let conn = Transport::new(framed, tx_control);
         let (sink, incoming) = conn.split();
-        let sender = Sender { tx: tx_outgoing };
+        let queue = Arc::new(SendQueue::default());
+        let sender = Sender {
+            tx: tx_outgoing,
+            batch_id: Arc::new(AtomicUsize::new(0)),
+            queue: queue.clone(),
+        };
 
         Ok(Client {
             incoming: Some(incoming),
             outgoing: Some(Outgoing {
                 sink,
                 stream: rx_outgoing,
+                control: rx_control,
                 buffered: None,
+                queue,
             }),
             sender,
             addr,
+            local_addr,
+            listener,
+            class: None,
+            authenticating: false,
+            state: Arc::new(state),
         })
     }
 
-    pub async fn send_to<M: Into<Message>>(& mut self, msg: M) -> Result<(), ProtocolError> {
-        self.sender.send(msg)
-    }
-
     pub fn set_hostame(&mut self, hostname: String) {
-        self.state.set_hostname(hostname);
+        Arc::make_mut(&mut self.state).set_hostname(hostname);
     }
 
     pub fn register(&mut self, nick: &String, user: &String, real: &String) {
-        self.state.register(nick, user, real);
+        Arc::make_mut(&mut self.state).register(nick, user, real);
+    }
+
+    pub fn set_cap(&mut self, cap: Capability, enabled: bool) {
+        Arc::make_mut(&mut self.state).set_cap(cap, enabled);
+    }
+
+    /// Returns false if the mode was already in the requested state.
+    pub fn set_mode(&mut self, mode: UserMode, enabled: bool) -> bool {
+        Arc::make_mut(&mut self.state).set_mode(mode, enabled)
+    }
+
+    pub fn set_cloak(&mut self, cloak: Option<String>) {
+        Arc::make_mut(&mut self.state).cloak = cloak;
+    }
+
+    /// Takes on the address and TLS status a `WEBIRC` gateway gave for its
+    /// user. The gateway's own certificate says nothing about the user, so
+    /// any fingerprint is dropped.
+    pub fn set_gateway_user(&mut self, addr: SocketAddr, secure: bool) {
+        self.addr = addr;
+        let state = Arc::make_mut(&mut self.state);
+        state.set_mode(UserMode::Secure, secure);
+        state.certfp = None;
+    }
+
+    pub fn set_ident(&mut self, ident: String) {
+        Arc::make_mut(&mut self.state).ident = Some(ident);
+    }
+
+    /// Marks the client as listed on a DNS blocklist.
+    pub fn add_listing(&mut self, reason: String) {
+        Arc::make_mut(&mut self.state).listings.push(reason);
+    }
+
+    pub fn set_privileges(&mut self, privileges: HashSet<Privilege>) {
+        Arc::make_mut(&mut self.state).privileges = privileges;
+    }
+
+    pub fn set_nick(&mut self, nick: String) {
+        Arc::make_mut(&mut self.state).set_nick(nick);
+    }
+
+    /// Returns false if the target was already monitored.
+    pub fn add_monitor(&mut self, target: String) -> bool {
+        Arc::make_mut(&mut self.state).monitoring.insert(target)
+    }
+
+    pub fn remove_monitor(&mut self, target: &str) -> bool {
+        Arc::make_mut(&mut self.state).monitoring.remove(target)
+    }
+
+    pub fn clear_monitors(&mut self) -> HashSet<String> {
+        std::mem::take(&mut Arc::make_mut(&mut self.state).monitoring)
+    }
+
+    pub fn set_realname(&mut self, realname: String) {
+        Arc::make_mut(&mut self.state).set_realname(realname);
+    }
+
+    pub fn set_account(&mut self, account: Option<String>) {
+        Arc::make_mut(&mut self.state).set_account(account);
+    }
+
+    pub fn authenticating(&self) -> bool {
+        self.authenticating
+    }
+
+    pub fn set_authenticating(&mut self, authenticating: bool) {
+        self.authenticating = authenticating;
+    }
+
+    pub fn set_away(&mut self, away: Option<String>) {
+        Arc::make_mut(&mut self.state).set_away(away);
+    }
+
+    /// The displayed host, which is the cloak while `+x` is set.
+    pub fn host(&self) -> String {
+        match self.state.cloak {
+            Some(ref cloak) if self.state.has_mode(UserMode::Cloaked) => cloak.clone(),
+            _ => self.real_host(),
+        }
+    }
+
+    /// The resolved hostname, falling back to the IP address until one is known.
+    pub fn real_host(&self) -> String {
+        if self.state.hostname.is_empty() {
+            self.addr.ip().to_string()
+        } else {
+            self.state.hostname.clone()
+        }
+    }
+
+    pub fn prefix(&self) -> Prefix {
+        Prefix::Nickname(
+            self.state.nick.clone(),
+            self.state.user.clone(),
+            self.host(),
+        )
     }
 
     pub fn state(&self) -> Arc<ClientState> {
@@ -227,7 +673,28 @@ impl Client {
     }
 
     pub fn address(&self) -> SocketAddr {
-        self.addr.clone()
+        self.addr
+    }
+
+    /// The address of the listener the client connected to.
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    /// Puts the client in a class, applying the class's sendq.
+    pub fn set_class(&mut self, class: ClassGuard) {
+        self.sender.queue.limit.store(class.class().sendq, Ordering::Relaxed);
+        self.class = Some(class);
+    }
+
+    /// Reads a message before the client's stream has been taken, without
+    /// sending anything queued for it.
+    pub async fn next_message(&mut self) -> Option<Result<Message>> {
+        self.incoming.as_mut().expect("Stream already configured").next().await
     }
 
     pub fn stream(&mut self) -> error::Result<ClientStream> {
@@ -240,7 +707,7 @@ impl Client {
 
     pub async fn poll_send(&mut self) -> Result<(), ProtocolError> {
         if let Some(outgoing) = self.outgoing.as_mut() {
-            outgoing.await.expect("Failed to poll outgoing messages");
+            outgoing.await?;
         }
         Ok(())
     }
@@ -250,4 +717,73 @@ impl Client {
     }
 }
 
-pub enum MessageError {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::Encoder;
+
+    fn sender(limit: usize) -> (Sender, UnboundedReceiver<Queued>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queue = SendQueue::default();
+        queue.limit.store(limit, Ordering::Relaxed);
+        let sender = Sender {
+            tx,
+            batch_id: Arc::new(AtomicUsize::new(0)),
+            queue: Arc::new(queue),
+        };
+        (sender, rx)
+    }
+
+    fn lines(rx: &mut UnboundedReceiver<Queued>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(queued) = rx.try_recv() {
+            lines.push(queued.msg.to_string().trim_end().to_owned());
+        }
+        lines
+    }
+
+    #[test]
+    fn queued_length_is_encoded_length() {
+        let (sender, mut rx) = sender(0);
+        let msg: Message = Command::PRIVMSG("#chan".to_owned(), "hello".to_owned(), None).into();
+        sender.send(msg.clone()).unwrap();
+        let mut encoded = bytes::BytesMut::new();
+        MessageCodec::new("utf-8").unwrap().encode(msg, &mut encoded).unwrap();
+        assert_eq!(rx.try_recv().unwrap().len, encoded.len());
+        assert_eq!(sender.queue.queued.load(Ordering::Relaxed), encoded.len());
+    }
+
+    #[test]
+    fn message_at_sendq_limit_is_accepted() {
+        let msg: Message = Command::PRIVMSG("#chan".to_owned(), "hello".to_owned(), None).into();
+        let (sender, _rx) = sender(msg.to_string().len());
+        sender.send(msg.clone()).unwrap();
+        assert!(matches!(sender.send(msg), Err(ProtocolError::SendQExceeded)));
+    }
+
+    #[test]
+    fn nested_batches() {
+        let (sender, mut rx) = sender(0);
+        let outer = sender
+            .batch(Some("irc.example".into()), "chathistory", vec!["#chan".to_owned()])
+            .unwrap();
+        let inner = outer
+            .nested("netsplit", vec!["a.example".to_owned(), "b.example".to_owned()])
+            .unwrap();
+        assert_eq!(outer.reference(), Some("1"));
+        assert_eq!(inner.reference(), Some("2"));
+        inner.send(Command::QUIT(Some("a.example b.example".to_owned()))).unwrap();
+        inner.end().unwrap();
+        outer.end().unwrap();
+        assert_eq!(
+            lines(&mut rx),
+            [
+                ":irc.example BATCH +1 chathistory #chan",
+                "@batch=1 :irc.example BATCH +2 netsplit a.example b.example",
+                "@batch=2 :irc.example QUIT :a.example b.example",
+                "@batch=1 :irc.example BATCH -2",
+                ":irc.example BATCH -1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::net::IpAddr;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Cloaking;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Derives cloaked hosts from a client's real host or IP address. The same
+/// host always gets the same cloak under the same keys, so bans on a cloak
+/// keep working across reconnects.
+#[derive(Debug, Clone)]
+pub struct Cloak {
+    keys: Vec<String>,
+    prefix: String,
+    default: bool,
+}
+
+impl Cloak {
+    /// Returns None if no keys are configured.
+    pub fn new(config: &Cloaking) -> Option<Cloak> {
+        if config.keys.is_empty() {
+            return None;
+        }
+        Some(Cloak {
+            keys: config.keys.clone(),
+            prefix: config.prefix.clone(),
+            default: config.default,
+        })
+    }
+
+    /// Whether clients are cloaked when they connect.
+    pub fn by_default(&self) -> bool {
+        self.default
+    }
+
+    /// Cloaks `host` if the client has one, otherwise its IP address.
+    pub fn cloak(&self, host: &str, ip: IpAddr) -> String {
+        if host.is_empty() {
+            self.cloak_ip(ip)
+        } else {
+            self.cloak_host(host)
+        }
+    }
+
+    /// Hashes the address and its enclosing networks separately, so that
+    /// clients from the same network share the trailing parts of their cloak:
+    /// `A1B2C3D4.E5F6A7B8.C9D0E1F2.IP`.
+    pub fn cloak_ip(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                format!(
+                    "{}.{}.{}.IP",
+                    self.hash(0, &ip.to_string()),
+                    self.hash(1, &format!("{}.{}.{}", a, b, c)),
+                    self.hash(2, &format!("{}.{}", a, b)),
+                )
+            }
+            IpAddr::V6(ip) => {
+                let s = ip.segments();
+                format!(
+                    "{}:{}:{}:IP",
+                    self.hash(0, &ip.to_string()),
+                    self.hash(1, &format!("{:x}:{:x}:{:x}:{:x}", s[0], s[1], s[2], s[3])),
+                    self.hash(2, &format!("{:x}:{:x}:{:x}", s[0], s[1], s[2])),
+                )
+            }
+        }
+    }
+
+    /// Replaces the first label of `host` with a hash, keeping the domain:
+    /// `cawcaw-A1B2C3D4.example.com`.
+    pub fn cloak_host(&self, host: &str) -> String {
+        let host = host.to_ascii_lowercase();
+        let hash = self.hash(0, &host);
+        match host.split_once('.') {
+            Some((_, domain)) if domain.contains('.') => {
+                format!("{}-{}.{}", self.prefix, hash, domain)
+            }
+            _ => format!("{}-{}", self.prefix, hash),
+        }
+    }
+
+    /// The first 32 bits of an HMAC-SHA256 of `input` as hex. Each part of a
+    /// cloak uses a different key where more than one is configured.
+    fn hash(&self, part: usize, input: &str) -> String {
+        let key = &self.keys[part % self.keys.len()];
+        let mut mac =
+            HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(input.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        digest[..4].iter().map(|b| format!("{:02X}", b)).collect()
+    }
+}
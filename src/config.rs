@@ -1,22 +1,142 @@
 use figment::{
     providers::{Env, Format, Serialized, Toml},
-    Figment,
+    Figment, Provider,
 };
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use toml_edit::{Array, ArrayOfTables, ImDocument, Item, TableLike, Value};
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Which TLS implementation a listener uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsBackend {
+    /// The platform's TLS library, through native-tls.
+    #[default]
+    Native,
+    /// rustls, if cawcaw was built with the `rustls` feature.
+    Rustls,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// A certificate served to clients asking for one of `hostnames` via SNI.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SniCert {
+    pub hostnames: Vec<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TLSCert {
+    /// The certificate chain served when no SNI certificate matches.
     pub cert: String,
     pub key: String,
+    #[serde(default)]
+    pub backend: TlsBackend,
+    /// The oldest protocol version clients may use.
+    #[serde(default = "TLSCert::default_min_version")]
+    pub min_version: TlsVersion,
+    /// Extra certificates chosen by SNI. rustls only.
+    #[serde(default)]
+    pub sni: Vec<SniCert>,
+    /// Seconds between checks for changed certificate files, which are then
+    /// loaded without restarting the listener, or 0 to never check.
+    /// rustls only.
+    #[serde(default)]
+    pub reload: u64,
+    /// Ask clients for a certificate, whose fingerprint identifies them
+    /// (CertFP). Any certificate is accepted, including self-signed ones.
+    /// rustls only.
+    #[serde(default)]
+    pub client_certs: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl TLSCert {
+    fn default_min_version() -> TlsVersion {
+        TlsVersion::Tls12
+    }
+}
+
+/// Settings for a listener taking IRC over WebSocket.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct WebSocket {
+    /// Masks of the `Origin`s browsers may connect from, e.g.
+    /// `https://*.example.org`. Any origin is allowed if empty.
+    #[serde(default)]
+    pub origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Listener {
     pub name: String,
-    pub address: SocketAddr,
+    /// Required unless `unix` is set.
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// Listen on a Unix domain socket instead of a TCP address.
+    pub unix: Option<UnixSocket>,
     pub tls: Option<TLSCert>,
+    /// Accept WebSocket connections instead of plain IRC, over TLS if `tls`
+    /// is also set.
+    pub websocket: Option<WebSocket>,
+    /// Expect a PROXY protocol header before anything else, giving the real
+    /// address of the client. Only proxies in `[proxy] trusted` may connect.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// TLS handshakes this listener runs at once. Further connections wait
+    /// to be accepted until one finishes.
+    #[serde(default = "Listener::default_max_handshakes")]
+    pub max_handshakes: usize,
+}
+
+impl Listener {
+    fn default_max_handshakes() -> usize {
+        64
+    }
+}
+
+/// A Unix domain socket listener, for local bots and bouncers.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UnixSocket {
+    pub path: String,
+    /// Permissions of the socket file, e.g. `0o660` to let a group connect.
+    #[serde(default = "UnixSocket::default_mode")]
+    pub mode: u32,
+    /// Address its clients are shown as connecting from, since they have none.
+    #[serde(default = "UnixSocket::default_address")]
+    pub address: IpAddr,
+    /// Hostname its clients are given in place of a DNS lookup.
+    #[serde(default = "UnixSocket::default_hostname")]
+    pub hostname: String,
+}
+
+impl UnixSocket {
+    fn default_mode() -> u32 {
+        0o600
+    }
+
+    fn default_address() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+
+    fn default_hostname() -> String {
+        "localhost".to_owned()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -24,9 +144,393 @@ pub struct Server {
     pub name: String,
     pub listeners: Vec<Listener>,
 }
+/// Which client-only (`+`) message tags are relayed between clients.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ClientTags {
+    /// Tags to relay, or every tag if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Tags never relayed, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl ClientTags {
+    pub fn permits(&self, key: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|t| t == key))
+            && !self.deny.iter().any(|t| t == key)
+    }
+}
+
+/// Settings for looking up the hostnames of connecting clients.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Dns {
+    /// Seconds to wait for the reverse and forward lookups together.
+    #[serde(default = "Dns::default_timeout")]
+    pub timeout: u64,
+    /// Nameservers to query instead of the system's resolv.conf.
+    #[serde(default)]
+    pub nameservers: Vec<SocketAddr>,
+}
+
+impl Dns {
+    fn default_timeout() -> u64 {
+        5
+    }
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self {
+            timeout: Dns::default_timeout(),
+            nameservers: Vec::new(),
+        }
+    }
+}
+
+/// Settings for RFC 1413 ident lookups.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Ident {
+    #[serde(default = "Ident::default_enabled")]
+    pub enabled: bool,
+    /// Seconds to wait for the client's identd to answer.
+    #[serde(default = "Ident::default_timeout")]
+    pub timeout: u64,
+    /// The port identd is queried on.
+    #[serde(default = "Ident::default_port")]
+    pub port: u16,
+}
+
+impl Ident {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_timeout() -> u64 {
+        5
+    }
+    fn default_port() -> u16 {
+        113
+    }
+}
+
+impl Default for Ident {
+    fn default() -> Self {
+        Self {
+            enabled: Ident::default_enabled(),
+            timeout: Ident::default_timeout(),
+            port: Ident::default_port(),
+        }
+    }
+}
+
+/// Limits on how quickly new connections are accepted, checked before any
+/// TLS handshake.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Throttle {
+    /// Connections allowed from one address or network per `window`.
+    #[serde(default = "Throttle::default_connections")]
+    pub connections: usize,
+    /// Length of the throttle window in seconds.
+    #[serde(default = "Throttle::default_window")]
+    pub window: u64,
+    #[serde(default = "Throttle::default_cidr_v4")]
+    pub cidr_v4: u8,
+    #[serde(default = "Throttle::default_cidr_v6")]
+    pub cidr_v6: u8,
+    /// Networks which are never throttled.
+    #[serde(default)]
+    pub exempt: Vec<IpNet>,
+    /// Seconds a client has to complete the TLS handshake.
+    #[serde(default = "Throttle::default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+impl Throttle {
+    fn default_connections() -> usize {
+        4
+    }
+    fn default_window() -> u64 {
+        60
+    }
+    fn default_cidr_v4() -> u8 {
+        32
+    }
+    fn default_cidr_v6() -> u8 {
+        64
+    }
+    fn default_handshake_timeout() -> u64 {
+        10
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            connections: Throttle::default_connections(),
+            window: Throttle::default_window(),
+            cidr_v4: Throttle::default_cidr_v4(),
+            cidr_v6: Throttle::default_cidr_v6(),
+            exempt: Vec::new(),
+            handshake_timeout: Throttle::default_handshake_timeout(),
+        }
+    }
+}
+
+/// Limits and settings shared by a group of connections.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Class {
+    pub name: String,
+    /// Connections allowed in this class at once.
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    /// Connections allowed from one address in this class.
+    #[serde(default)]
+    pub max_per_ip: Option<usize>,
+    /// Connections allowed from one network in this class, sized by
+    /// `cidr_v4` and `cidr_v6`.
+    #[serde(default)]
+    pub max_per_cidr: Option<usize>,
+    #[serde(default = "Class::default_cidr_v4")]
+    pub cidr_v4: u8,
+    #[serde(default = "Class::default_cidr_v6")]
+    pub cidr_v6: u8,
+    /// Seconds of silence before a client is sent a PING, and again before
+    /// it is disconnected for not answering.
+    #[serde(default = "Class::default_ping_frequency")]
+    pub ping_frequency: u64,
+    /// Bytes which may be queued for a client before it is disconnected.
+    #[serde(default = "Class::default_sendq")]
+    pub sendq: usize,
+}
+
+impl Class {
+    pub const DEFAULT: &'static str = "default";
+
+    fn default_cidr_v4() -> u8 {
+        24
+    }
+    fn default_cidr_v6() -> u8 {
+        48
+    }
+    fn default_ping_frequency() -> u64 {
+        120
+    }
+    fn default_sendq() -> usize {
+        1 << 20
+    }
+
+    /// The class clients are put in when no `[[class]]` blocks are configured.
+    pub fn fallback() -> Class {
+        Class {
+            name: Class::DEFAULT.to_owned(),
+            max_clients: None,
+            max_per_ip: None,
+            max_per_cidr: None,
+            cidr_v4: Class::default_cidr_v4(),
+            cidr_v6: Class::default_cidr_v6(),
+            ping_frequency: Class::default_ping_frequency(),
+            sendq: Class::default_sendq(),
+        }
+    }
+}
+
+/// Puts matching connections in a class. Every condition given must match;
+/// the first matching block in the file is used.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Allow {
+    pub class: String,
+    /// An address or network, e.g. `192.0.2.0/24`.
+    #[serde(default)]
+    pub ip: Option<IpNet>,
+    /// A hostname mask, e.g. `*.example.com`.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// The name of the listener the client connected to.
+    #[serde(default)]
+    pub listener: Option<String>,
+    #[serde(default)]
+    pub tls: Option<bool>,
+}
+
+/// What happens to a client found on a DNS blocklist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsblAction {
+    /// Disconnect the client before it registers.
+    Reject,
+    /// Let the client in, but show the listing to operators in WHOIS.
+    Mark,
+    /// Let the client in and tell operators with `+s`.
+    Notice,
+}
+
+/// A DNS blocklist checked when clients connect.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Dnsbl {
+    pub zone: String,
+    /// Descriptions of the reply addresses which count as a listing, e.g.
+    /// `"127.0.0.3" = "open proxy"`. If empty, any reply is a listing.
+    #[serde(default)]
+    pub replies: HashMap<String, String>,
+    pub action: DnsblAction,
+    /// Shown to the client or operators. `{ip}`, `{zone}` and `{reply}` are
+    /// replaced with the client's address, the zone and the reply's description.
+    #[serde(default = "Dnsbl::default_reason")]
+    pub reason: String,
+}
+
+impl Dnsbl {
+    fn default_reason() -> String {
+        "Your address {ip} is listed in {zone} ({reply})".to_owned()
+    }
+}
+
+/// Settings for cloaked hosts (user mode `+x`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Cloaking {
+    /// Secret keys the cloaks are derived from. Cloaking is disabled if empty.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Prepended to cloaked hostnames.
+    #[serde(default = "Cloaking::default_prefix")]
+    pub prefix: String,
+    /// Whether clients are cloaked when they connect.
+    #[serde(default)]
+    pub default: bool,
+}
+
+impl Cloaking {
+    fn default_prefix() -> String {
+        "cawcaw".to_owned()
+    }
+}
+
+impl Default for Cloaking {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            prefix: Cloaking::default_prefix(),
+            default: false,
+        }
+    }
+}
+
+/// Proxies which may connect to `proxy_protocol` listeners.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Proxy {
+    #[serde(default)]
+    pub trusted: Vec<IpNet>,
+}
+
+/// A web gateway which may pass on its users' addresses with `WEBIRC`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebIrc {
+    /// Shown to operators in notices about the gateway.
+    pub name: String,
+    pub password: String,
+    /// Addresses or networks the gateway connects from.
+    pub hosts: Vec<IpNet>,
+}
+
+/// Settings for the `sts` capability, which tells clients to use TLS. It
+/// isn't offered on Unix socket or WebSocket listeners, whose clients couldn't
+/// follow it to a TLS port.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sts {
+    #[serde(default = "Sts::default_enabled")]
+    pub enabled: bool,
+    /// Port plaintext clients are sent to. Defaults to that of the first
+    /// TLS listener.
+    pub port: Option<u16>,
+    /// Seconds clients should keep to TLS after seeing the policy.
+    #[serde(default = "Sts::default_duration")]
+    pub duration: u64,
+    /// Whether the policy may be preloaded into clients.
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl Sts {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_duration() -> u64 {
+        30 * 24 * 60 * 60
+    }
+}
+
+impl Default for Sts {
+    fn default() -> Self {
+        Self {
+            enabled: Sts::default_enabled(),
+            port: None,
+            duration: Sts::default_duration(),
+            preload: false,
+        }
+    }
+}
+
+/// Privileges which may be granted to operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    /// See the real hosts of cloaked users.
+    Auspex,
+    /// Reload the configuration with `REHASH`.
+    Rehash,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Oper {
+    pub name: String,
+    /// May be left empty if `certfp` is set, to log in by certificate alone.
+    #[serde(default)]
+    pub password: String,
+    /// SHA-256 or SHA-512 fingerprint of the TLS client certificate the
+    /// operator must connect with.
+    pub certfp: Option<String>,
+    #[serde(default)]
+    pub privileges: Vec<Privilege>,
+}
+
+/// An account clients log in to with SASL EXTERNAL, using a TLS client
+/// certificate.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Account {
+    pub name: String,
+    /// SHA-256 or SHA-512 fingerprints of the certificates which may log in.
+    pub certfp: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub server: Server,
+    #[serde(default)]
+    pub client_tags: ClientTags,
+    #[serde(default)]
+    pub dns: Dns,
+    #[serde(default)]
+    pub ident: Ident,
+    #[serde(default)]
+    pub dnsbl: Vec<Dnsbl>,
+    #[serde(default)]
+    pub throttle: Throttle,
+    #[serde(default)]
+    pub class: Vec<Class>,
+    #[serde(default)]
+    pub allow: Vec<Allow>,
+    #[serde(default)]
+    pub cloak: Cloaking,
+    #[serde(default)]
+    pub sts: Sts,
+    #[serde(default)]
+    pub proxy: Proxy,
+    #[serde(default)]
+    pub webirc: Vec<WebIrc>,
+    #[serde(default)]
+    pub oper: Vec<Oper>,
+    #[serde(default)]
+    pub account: Vec<Account>,
 }
 
 impl Default for Config {
@@ -36,20 +540,422 @@ impl Default for Config {
                 name: "localhost".to_string(),
                 listeners: vec![Listener {
                     name: "plain".to_owned(),
-                    address: "127.0.0.1:6667".parse::<SocketAddr>().unwrap(),
+                    address: Some("127.0.0.1:6667".parse::<SocketAddr>().unwrap()),
+                    unix: None,
                     tls: None,
+                    websocket: None,
+                    proxy_protocol: false,
+                    max_handshakes: Listener::default_max_handshakes(),
                 }],
             },
+            client_tags: ClientTags::default(),
+            dns: Dns::default(),
+            ident: Ident::default(),
+            dnsbl: Vec::new(),
+            throttle: Throttle::default(),
+            class: Vec::new(),
+            allow: Vec::new(),
+            cloak: Cloaking::default(),
+            sts: Sts::default(),
+            proxy: Proxy::default(),
+            webirc: Vec::new(),
+            oper: Vec::new(),
+            account: Vec::new(),
         }
     }
 }
 
 impl Config {
-    pub fn new(config: &Path) -> Result<Config, figment::Error> {
-        let config: Config = Figment::from(Serialized::defaults(Config::default()))
-            .merge(Toml::file(config))
-            .merge(Env::prefixed("CAW_"))
-            .extract()?;
+    /// Reads and checks the config file, returning every mistake found.
+    pub fn new(path: &Path) -> Result<Config, ConfigErrors> {
+        let error = |line, key, message| ConfigError {
+            file: path.to_owned(),
+            line,
+            key,
+            message,
+        };
+        let source = fs::read_to_string(path).map_err(|e| ConfigErrors(vec![error(None, Vec::new(), e.to_string())]))?;
+        let document = ImDocument::parse(source.as_str()).map_err(|e| {
+            let line = e.span().map(|span| line_of(&source, span.start));
+            let message: Vec<&str> = e.message().lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+            ConfigErrors(vec![error(line, Vec::new(), message.join(": "))])
+        })?;
+
+        let toml = Toml::string(&source);
+        let toml_name = toml.metadata().name;
+        let figment = Figment::from(Serialized::defaults(Config::default()))
+            .merge(toml)
+            .merge(Env::prefixed("CAW_"));
+        // Values set by environment variables aren't in the file.
+        let line = |key: &[String], from_file: bool| if from_file { locate(&source, &document, key) } else { None };
+        let config: Config = figment.extract().map_err(|errors| {
+            ConfigErrors(
+                errors
+                    .into_iter()
+                    .map(|e| {
+                        let from_file = e.metadata.as_ref().is_some_and(|m| m.name == toml_name);
+                        error(line(&e.path, from_file), e.path, e.kind.to_string())
+                    })
+                    .collect(),
+            )
+        })?;
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(ConfigErrors(
+                problems
+                    .into_iter()
+                    .map(|(key, message)| {
+                        // Figment can only look up tables, not array elements.
+                        let table: Vec<&str> = key
+                            .iter()
+                            .take_while(|k| k.parse::<usize>().is_err() && !k.contains('.'))
+                            .map(String::as_str)
+                            .collect();
+                        let from_file = figment.find_metadata(&table.join(".")).is_some_and(|m| m.name == toml_name);
+                        error(line(&key, from_file), key, message)
+                    })
+                    .collect(),
+            ));
+        }
         Ok(config)
     }
+
+    /// Finds values which are well formed but can't work, such as listeners
+    /// sharing an address or `[[allow]]` blocks naming missing classes.
+    fn validate(&self) -> Vec<(Vec<String>, String)> {
+        let mut problems = Vec::new();
+
+        if self.server.name.is_empty() || self.server.name.contains(char::is_whitespace) {
+            problems.push((key(&[&"server", &"name"]), "must be a hostname".to_owned()));
+        }
+
+        let mut listeners: HashMap<&str, &Listener> = HashMap::new();
+        for (i, listener) in self.server.listeners.iter().enumerate() {
+            let at = |fields: &[&dyn fmt::Display]| [key(&[&"server", &"listeners", &i]), key(fields)].concat();
+
+            if listeners.contains_key(listener.name.as_str()) {
+                problems.push((at(&[&"name"]), format!("another listener is already called `{}`", listener.name)));
+            }
+            for other in listeners.values() {
+                if let (Some(a), Some(b)) = (listener.address, other.address) {
+                    let unspecified = a.ip().is_unspecified() || b.ip().is_unspecified();
+                    if a.port() == b.port() && (a.ip() == b.ip() || (unspecified && a.is_ipv4() == b.is_ipv4())) {
+                        problems.push((at(&[&"address"]), format!("listener `{}` already uses {}", other.name, b)));
+                    }
+                }
+                if let (Some(a), Some(b)) = (&listener.unix, &other.unix) {
+                    if a.path == b.path {
+                        problems.push((at(&[&"unix"]), format!("listener `{}` already uses {}", other.name, b.path)));
+                    }
+                }
+            }
+            listeners.entry(&listener.name).or_insert(listener);
+
+            match (&listener.address, &listener.unix) {
+                (Some(_), Some(_)) => {
+                    problems.push((at(&[]), "needs either an address or a unix socket, not both".to_owned()));
+                }
+                (None, None) => problems.push((at(&[]), "needs an address or a unix socket".to_owned())),
+                (None, Some(unix)) => {
+                    if listener.tls.is_some() || listener.websocket.is_some() || listener.proxy_protocol {
+                        problems.push((
+                            at(&[&"unix"]),
+                            "unix socket listeners can't use TLS, WebSocket or the PROXY protocol".to_owned(),
+                        ));
+                    }
+                    if unix.mode > 0o777 {
+                        problems.push((at(&[&"unix", &"mode"]), "must be permissions such as 0o660".to_owned()));
+                    }
+                }
+                (Some(_), None) => {}
+            }
+            if listener.proxy_protocol && self.proxy.trusted.is_empty() {
+                problems.push((
+                    at(&[&"proxy_protocol"]),
+                    "no proxies are listed in [proxy] trusted, so every connection would be refused".to_owned(),
+                ));
+            }
+            if listener.max_handshakes == 0 {
+                problems.push((at(&[&"max_handshakes"]), "must be at least 1".to_owned()));
+            }
+            if let Some(websocket) = &listener.websocket {
+                for (j, origin) in websocket.origins.iter().enumerate() {
+                    if !(origin == "*" || origin.contains("://")) || origin.contains(char::is_whitespace) {
+                        problems.push((
+                            at(&[&"websocket", &"origins", &j]),
+                            format!("`{}` is not an origin mask such as https://*.example.org", origin),
+                        ));
+                    }
+                }
+            }
+
+            let Some(tls) = &listener.tls else { continue };
+            if tls.backend == TlsBackend::Rustls && !cfg!(feature = "rustls") {
+                problems.push((at(&[&"tls", &"backend"]), "cawcaw was built without the rustls feature".to_owned()));
+            }
+            if tls.backend == TlsBackend::Native {
+                if tls.min_version == TlsVersion::Tls13 {
+                    problems.push((at(&[&"tls", &"min_version"]), "TLS 1.3 needs the rustls backend".to_owned()));
+                }
+                for (field, set) in [("sni", !tls.sni.is_empty()), ("reload", tls.reload != 0), ("client_certs", tls.client_certs)] {
+                    if set {
+                        problems.push((at(&[&"tls", &field]), "needs the rustls backend".to_owned()));
+                    }
+                }
+            }
+            let mut files = vec![(at(&[&"tls", &"cert"]), &tls.cert), (at(&[&"tls", &"key"]), &tls.key)];
+            for (j, sni) in tls.sni.iter().enumerate() {
+                files.push((at(&[&"tls", &"sni", &j, &"cert"]), &sni.cert));
+                files.push((at(&[&"tls", &"sni", &j, &"key"]), &sni.key));
+            }
+            for (key, file) in files {
+                if let Err(e) = fs::File::open(file) {
+                    problems.push((key, format!("can't read {}: {}", file, e)));
+                }
+            }
+        }
+
+        if self.throttle.connections == 0 {
+            problems.push((key(&[&"throttle", &"connections"]), "must be at least 1".to_owned()));
+        }
+        check_prefixes(&mut problems, &[&"throttle"], self.throttle.cidr_v4, self.throttle.cidr_v6);
+
+        let mut classes = HashSet::new();
+        for (i, class) in self.class.iter().enumerate() {
+            if !classes.insert(class.name.as_str()) {
+                problems.push((key(&[&"class", &i, &"name"]), format!("another class is already called `{}`", class.name)));
+            }
+            check_prefixes(&mut problems, &[&"class", &i], class.cidr_v4, class.cidr_v6);
+            if class.ping_frequency == 0 {
+                problems.push((key(&[&"class", &i, &"ping_frequency"]), "must be at least 1".to_owned()));
+            }
+        }
+        for (i, allow) in self.allow.iter().enumerate() {
+            if allow.class != Class::DEFAULT && !classes.contains(allow.class.as_str()) {
+                problems.push((key(&[&"allow", &i, &"class"]), format!("there is no class called `{}`", allow.class)));
+            }
+            if let Some(host) = &allow.host {
+                let valid = |c: char| c.is_ascii_alphanumeric() || "-.:*?".contains(c);
+                if host.is_empty() || !host.chars().all(valid) {
+                    problems.push((
+                        key(&[&"allow", &i, &"host"]),
+                        format!("`{}` is not a hostname mask such as *.example.com", host),
+                    ));
+                }
+            }
+            if let Some(name) = &allow.listener {
+                if !listeners.contains_key(name.as_str()) {
+                    problems.push((key(&[&"allow", &i, &"listener"]), format!("there is no listener called `{}`", name)));
+                }
+            }
+        }
+
+        for (i, dnsbl) in self.dnsbl.iter().enumerate() {
+            if dnsbl.zone.is_empty() || dnsbl.zone.contains(char::is_whitespace) {
+                problems.push((key(&[&"dnsbl", &i, &"zone"]), "must be a DNS zone".to_owned()));
+            }
+            for reply in dnsbl.replies.keys() {
+                if reply.parse::<IpAddr>().is_err() {
+                    problems.push((key(&[&"dnsbl", &i, &"replies", reply]), format!("`{}` is not an address", reply)));
+                }
+            }
+        }
+
+        let mut gateways = HashSet::new();
+        for (i, webirc) in self.webirc.iter().enumerate() {
+            if !gateways.insert(webirc.name.as_str()) {
+                problems.push((
+                    key(&[&"webirc", &i, &"name"]),
+                    format!("another gateway is already called `{}`", webirc.name),
+                ));
+            }
+            if webirc.password.is_empty() {
+                problems.push((key(&[&"webirc", &i, &"password"]), "must not be empty".to_owned()));
+            }
+            if webirc.hosts.is_empty() {
+                problems.push((key(&[&"webirc", &i, &"hosts"]), "must list the gateway's addresses".to_owned()));
+            }
+        }
+
+        let mut opers = HashSet::new();
+        for (i, oper) in self.oper.iter().enumerate() {
+            if !opers.insert(oper.name.as_str()) {
+                problems.push((key(&[&"oper", &i, &"name"]), format!("another operator is already called `{}`", oper.name)));
+            }
+            match &oper.certfp {
+                None if oper.password.is_empty() => {
+                    problems.push((key(&[&"oper", &i]), "needs a password or a certfp".to_owned()));
+                }
+                Some(certfp) if !valid_certfp(certfp) => {
+                    problems.push((key(&[&"oper", &i, &"certfp"]), CERTFP_FORMAT.to_owned()));
+                }
+                _ => {}
+            }
+        }
+
+        let mut accounts = HashSet::new();
+        let mut certfps = HashSet::new();
+        for (i, account) in self.account.iter().enumerate() {
+            if account.name.is_empty() || account.name.contains([' ', '*']) {
+                problems.push((key(&[&"account", &i, &"name"]), "must not be empty or contain spaces or `*`".to_owned()));
+            } else if !accounts.insert(account.name.as_str()) {
+                problems.push((key(&[&"account", &i, &"name"]), format!("another account is already called `{}`", account.name)));
+            }
+            if account.certfp.is_empty() {
+                problems.push((key(&[&"account", &i, &"certfp"]), "must list at least one fingerprint".to_owned()));
+            }
+            for (j, certfp) in account.certfp.iter().enumerate() {
+                if !valid_certfp(certfp) {
+                    problems.push((key(&[&"account", &i, &"certfp", &j]), CERTFP_FORMAT.to_owned()));
+                } else if !certfps.insert(certfp.replace(':', "").to_ascii_lowercase()) {
+                    problems.push((key(&[&"account", &i, &"certfp", &j]), "already logs in to another account".to_owned()));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+const CERTFP_FORMAT: &str = "must be a SHA-256 or SHA-512 fingerprint in hex";
+
+fn valid_certfp(certfp: &str) -> bool {
+    let hex: Vec<char> = certfp.chars().filter(|c| *c != ':').collect();
+    (hex.len() == 64 || hex.len() == 128) && hex.iter().all(char::is_ascii_hexdigit)
+}
+
+fn key(parts: &[&dyn fmt::Display]) -> Vec<String> {
+    parts.iter().map(|part| part.to_string()).collect()
+}
+
+fn check_prefixes(problems: &mut Vec<(Vec<String>, String)>, table: &[&dyn fmt::Display], v4: u8, v6: u8) {
+    for (field, prefix, max) in [("cidr_v4", v4, 32), ("cidr_v6", v6, 128)] {
+        if prefix > max {
+            problems.push(([key(table), vec![field.to_owned()]].concat(), format!("must be at most {}", max)));
+        }
+    }
+}
+
+/// A mistake in the config file.
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    /// The line the mistake is on, if it's in the file rather than an
+    /// environment variable.
+    pub line: Option<usize>,
+    /// Keys and array indices leading to the value, e.g.
+    /// `["server", "listeners", "1", "address"]`.
+    pub key: Vec<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        for (i, part) in self.key.iter().enumerate() {
+            if i == 0 {
+                f.write_str(": ")?;
+            }
+            if part.parse::<usize>().is_ok() {
+                write!(f, "[{}]", part)?;
+                continue;
+            }
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            if part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                f.write_str(part)?;
+            } else {
+                write!(f, "{:?}", part)?;
+            }
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Every mistake found in a config file, one per line when displayed.
+#[derive(Debug, Clone)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+enum Node<'a> {
+    Table(&'a dyn TableLike),
+    Tables(&'a ArrayOfTables),
+    Array(&'a Array),
+    Value,
+}
+
+impl<'a> Node<'a> {
+    fn of_item(item: &'a Item) -> Node<'a> {
+        match item {
+            Item::Table(table) => Node::Table(table),
+            Item::ArrayOfTables(tables) => Node::Tables(tables),
+            Item::Value(value) => Node::of_value(value),
+            Item::None => Node::Value,
+        }
+    }
+
+    fn of_value(value: &'a Value) -> Node<'a> {
+        match value {
+            Value::InlineTable(table) => Node::Table(table),
+            Value::Array(array) => Node::Array(array),
+            _ => Node::Value,
+        }
+    }
+}
+
+/// The line `key` is set on in the file, or if it isn't, the line of the
+/// nearest table containing it.
+fn locate(source: &str, document: &ImDocument<&str>, key: &[String]) -> Option<usize> {
+    let mut node = Node::Table(document.as_table());
+    let mut span = None;
+    for part in key {
+        let index = part.parse::<usize>().ok();
+        node = match node {
+            Node::Table(table) => match table.get_key_value(part) {
+                Some((key, item)) => {
+                    span = key.span().or(item.span()).or(span);
+                    Node::of_item(item)
+                }
+                None => break,
+            },
+            Node::Tables(tables) => match index.and_then(|i| tables.get(i)) {
+                Some(table) => {
+                    span = table.span().or(span);
+                    Node::Table(table)
+                }
+                None => break,
+            },
+            Node::Array(array) => match index.and_then(|i| array.get(i)) {
+                Some(value) => {
+                    span = value.span().or(span);
+                    Node::of_value(value)
+                }
+                None => break,
+            },
+            Node::Value => break,
+        };
+    }
+    span.map(|span| line_of(source, span.start))
 }
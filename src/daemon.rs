@@ -0,0 +1,63 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::process;
+
+use crate::logger;
+
+/// Forks into the background, returning in the child. The parent waits for
+/// the child to call [`Ready::detach`], and exits successfully only if it
+/// does, so whatever started cawcaw learns whether it failed to start.
+///
+/// Must be called before any threads are started.
+pub fn daemonize() -> io::Result<Ready> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe just opened these, and nothing else owns them.
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: the process has one thread, so the child gets a consistent copy.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            // Leave the terminal's session, so closing it doesn't hang us up.
+            // SAFETY: the child of a fork is never a process group leader.
+            if unsafe { libc::setsid() } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Ready(write))
+        }
+        _ => {
+            drop(write);
+            // The child writes a byte once it's running, or exits without
+            // writing anything, closing the pipe.
+            let mut byte = [0];
+            let started = matches!(read.read(&mut byte), Ok(1));
+            process::exit(if started { 0 } else { 1 });
+        }
+    }
+}
+
+/// Lets the parent of a daemonized server exit.
+#[derive(Debug)]
+pub struct Ready(File);
+
+impl Ready {
+    /// Lets the parent exit successfully, and closes stdin, stdout and
+    /// stderr, logging to syslog from now on.
+    pub fn detach(mut self) -> io::Result<()> {
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        logger::use_syslog();
+        for fd in 0..3 {
+            // SAFETY: both are open descriptors.
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.0.write_all(&[0])
+    }
+}
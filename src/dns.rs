@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tokio::sync::Mutex;
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::config::Dns;
+
+/// Maximum length of a hostname shown to other clients.
+pub const HOSTLEN: usize = 63;
+
+/// How long a failed lookup is remembered when DNS gave no TTL for it.
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Error)]
+pub enum LookupError {
+    #[error("timed out")]
+    Timeout,
+    #[error("{0}")]
+    Resolve(String),
+    #[error("hostname {0} is invalid")]
+    Invalid(String),
+    #[error("hostname {0} does not resolve to your address")]
+    NotConfirmed(String),
+}
+
+impl From<ResolveError> for LookupError {
+    fn from(e: ResolveError) -> Self {
+        LookupError::Resolve(e.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct CachedHost {
+    host: Result<String, LookupError>,
+    valid_until: Instant,
+}
+
+/// Resolves the hostnames of connecting clients with forward-confirmed
+/// reverse DNS: a PTR record is only used if one of its A or AAAA records is
+/// the client's address. Results are cached for as long as the records' TTLs.
+#[derive(Debug, Clone)]
+pub struct HostResolver {
+    resolver: TokioAsyncResolver,
+    timeout: Duration,
+    cache: Arc<Mutex<HashMap<IpAddr, CachedHost>>>,
+}
+
+impl HostResolver {
+    pub fn new(config: &Dns) -> Result<HostResolver, ResolveError> {
+        let resolver = if config.nameservers.is_empty() {
+            TokioAsyncResolver::tokio_from_system_conf()?
+        } else {
+            let nameservers: Vec<NameServerConfig> = config
+                .nameservers
+                .iter()
+                .flat_map(|addr| {
+                    [
+                        NameServerConfig::new(*addr, Protocol::Udp),
+                        NameServerConfig::new(*addr, Protocol::Tcp),
+                    ]
+                })
+                .collect();
+            let config = ResolverConfig::from_parts(None, Vec::new(), nameservers);
+            TokioAsyncResolver::tokio(config, ResolverOpts::default())
+        };
+        Ok(HostResolver {
+            resolver,
+            timeout: Duration::from_secs(config.timeout),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn resolver(&self) -> &TokioAsyncResolver {
+        &self.resolver
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns the verified hostname of `ip`.
+    pub async fn lookup(&self, ip: IpAddr) -> Result<String, LookupError> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        if let Some(cached) = self.cache.lock().await.get(&ip) {
+            if cached.valid_until > now {
+                return cached.host.clone();
+            }
+        }
+        let (host, valid_until) = match tokio::time::timeout(self.timeout, self.confirm(ip)).await {
+            Ok(result) => result,
+            // Don't remember timeouts; the nameserver may just be slow.
+            Err(_) => return Err(LookupError::Timeout),
+        };
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, c| c.valid_until > now);
+        cache.insert(
+            ip,
+            CachedHost {
+                host: host.clone(),
+                valid_until,
+            },
+        );
+        host
+    }
+
+    /// Looks up the PTR records of `ip` and returns the first valid name
+    /// which resolves back to it, along with when the answer expires.
+    async fn confirm(&self, ip: IpAddr) -> (Result<String, LookupError>, Instant) {
+        let ptr = match self.resolver.reverse_lookup(ip).await {
+            Ok(ptr) => ptr,
+            Err(e) => return (Err(e.into()), Instant::now() + NEGATIVE_TTL),
+        };
+        let mut valid_until = ptr.valid_until();
+        let mut error = None;
+        for name in ptr.iter() {
+            let host = name.to_utf8().trim_end_matches('.').to_ascii_lowercase();
+            if !valid_hostname(&host) {
+                error.get_or_insert(LookupError::Invalid(host));
+                continue;
+            }
+            match self.resolver.lookup_ip(format!("{}.", host)).await {
+                Ok(addrs) => {
+                    valid_until = valid_until.min(addrs.valid_until());
+                    if addrs.iter().any(|a| a.to_canonical() == ip) {
+                        return (Ok(host), valid_until);
+                    }
+                    error.get_or_insert(LookupError::NotConfirmed(host));
+                }
+                Err(e) => {
+                    error.get_or_insert(e.into());
+                }
+            }
+        }
+        let error = error.unwrap_or_else(|| LookupError::Resolve("no PTR records".to_owned()));
+        (Err(error), valid_until)
+    }
+}
+
+/// Whether `host` is safe to show as a client's hostname: dot-separated
+/// labels of letters, digits and hyphens, which isn't itself an IP address.
+pub fn valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= HOSTLEN
+        && host.parse::<IpAddr>().is_err()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::net::UdpSocket;
+    use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::{A, PTR};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 10);
+
+    type Records = Arc<StdMutex<Vec<Record>>>;
+
+    /// Answers queries on loopback from `records`, which may be changed
+    /// while it runs.
+    async fn stub_nameserver(records: Records) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(query) = Message::from_vec(&buf[..len]) else { continue };
+                let mut reply = Message::new();
+                reply
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(query.op_code())
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(query.queries().to_vec());
+                for question in query.queries() {
+                    let answers: Vec<Record> = records
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|r| r.name() == question.name() && r.record_type() == question.query_type())
+                        .cloned()
+                        .collect();
+                    reply.add_answers(answers);
+                }
+                if reply.answers().is_empty() {
+                    reply.set_response_code(ResponseCode::NXDomain);
+                }
+                let _ = socket.send_to(&reply.to_vec().unwrap(), peer).await;
+            }
+        });
+        addr
+    }
+
+    fn records(host: &str, addr: Ipv4Addr, ttl: u32) -> Vec<Record> {
+        let reverse = Name::from_ascii("10.2.0.192.in-addr.arpa.").unwrap();
+        let host = Name::from_ascii(host).unwrap();
+        vec![
+            Record::from_rdata(reverse, ttl, RData::PTR(PTR(host.clone()))),
+            Record::from_rdata(host, ttl, RData::A(A(addr))),
+        ]
+    }
+
+    fn resolver(nameserver: SocketAddr) -> HostResolver {
+        HostResolver::new(&Dns {
+            timeout: 1,
+            nameservers: vec![nameserver],
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn forward_confirmed() {
+        let nameserver = stub_nameserver(Arc::new(StdMutex::new(records("host.example.org.", CLIENT, 300)))).await;
+        let host = resolver(nameserver).lookup(IpAddr::V4(CLIENT)).await;
+        assert_eq!(host.unwrap(), "host.example.org");
+    }
+
+    #[tokio::test]
+    async fn forward_mismatch() {
+        let other = Ipv4Addr::new(192, 0, 2, 99);
+        let nameserver = stub_nameserver(Arc::new(StdMutex::new(records("host.example.org.", other, 300)))).await;
+        let host = resolver(nameserver).lookup(IpAddr::V4(CLIENT)).await;
+        assert!(matches!(host, Err(LookupError::NotConfirmed(h)) if h == "host.example.org"));
+    }
+
+    #[tokio::test]
+    async fn lookup_times_out() {
+        // Bound but never read, so queries go unanswered.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = resolver(silent.local_addr().unwrap());
+        let started = Instant::now();
+        let host = resolver.lookup(IpAddr::V4(CLIENT)).await;
+        assert!(matches!(host, Err(LookupError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn cache_expires_with_ttl() {
+        let table: Records = Arc::new(StdMutex::new(records("old.example.org.", CLIENT, 1)));
+        let resolver = resolver(stub_nameserver(table.clone()).await);
+        assert_eq!(resolver.lookup(IpAddr::V4(CLIENT)).await.unwrap(), "old.example.org");
+
+        *table.lock().unwrap() = records("new.example.org.", CLIENT, 1);
+        assert_eq!(resolver.lookup(IpAddr::V4(CLIENT)).await.unwrap(), "old.example.org");
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(resolver.lookup(IpAddr::V4(CLIENT)).await.unwrap(), "new.example.org");
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::sync::Mutex;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::config::{Dnsbl, DnsblAction};
+use crate::dns::{HostResolver, NEGATIVE_TTL};
+
+/// A blocklist which lists a client's address.
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub zone: String,
+    pub action: DnsblAction,
+    /// The configured reason with the placeholders filled in.
+    pub reason: String,
+}
+
+#[derive(Debug)]
+struct CachedReply {
+    reply: Option<IpAddr>,
+    valid_until: Instant,
+}
+
+/// Checks connecting clients against the configured DNS blocklists.
+#[derive(Debug, Clone)]
+pub struct DnsblChecker {
+    lists: Vec<Dnsbl>,
+    resolver: TokioAsyncResolver,
+    timeout: Duration,
+    /* Zone and address to the blocklist's reply */
+    cache: Arc<Mutex<HashMap<(String, IpAddr), CachedReply>>>,
+}
+
+impl DnsblChecker {
+    pub fn new(lists: Vec<Dnsbl>, resolver: &HostResolver) -> DnsblChecker {
+        DnsblChecker {
+            lists,
+            resolver: resolver.resolver().clone(),
+            timeout: resolver.timeout(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queries every blocklist at once and returns those listing `ip`.
+    pub async fn check(&self, ip: IpAddr) -> Vec<Listing> {
+        let ip = ip.to_canonical();
+        let checks = self.lists.iter().map(|list| self.check_list(list, ip));
+        join_all(checks).await.into_iter().flatten().collect()
+    }
+
+    async fn check_list(&self, list: &Dnsbl, ip: IpAddr) -> Option<Listing> {
+        let reply = self.query(&list.zone, ip).await?;
+        let description = if list.replies.is_empty() {
+            reply.to_string()
+        } else {
+            list.replies.get(&reply.to_string())?.clone()
+        };
+        let reason = list
+            .reason
+            .replace("{ip}", &ip.to_string())
+            .replace("{zone}", &list.zone)
+            .replace("{reply}", &description);
+        Some(Listing {
+            zone: list.zone.clone(),
+            action: list.action,
+            reason,
+        })
+    }
+
+    /// Returns the address the zone answers with for `ip`, if any. Lists
+    /// which fail to answer in time are treated as not listing the client.
+    async fn query(&self, zone: &str, ip: IpAddr) -> Option<IpAddr> {
+        let key = (zone.to_owned(), ip);
+        let now = Instant::now();
+        if let Some(cached) = self.cache.lock().await.get(&key) {
+            if cached.valid_until > now {
+                return cached.reply;
+            }
+        }
+        let name = format!("{}.{}.", reversed(ip), zone.trim_end_matches('.'));
+        let lookup = tokio::time::timeout(self.timeout, self.resolver.ipv4_lookup(name)).await.ok()?;
+        let (reply, valid_until) = match lookup {
+            Ok(lookup) => (lookup.iter().next().map(|a| IpAddr::V4(Ipv4Addr::from(*a))), lookup.valid_until()),
+            Err(_) => (None, now + NEGATIVE_TTL),
+        };
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, c| c.valid_until > now);
+        cache.insert(key, CachedReply { reply, valid_until });
+        reply
+    }
+}
+
+/// The address in the reversed form blocklists are queried with:
+/// `4.3.2.1` for `1.2.3.4`, and reversed nibbles for IPv6.
+fn reversed(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|b| [b & 0xf, b >> 4])
+            .map(|n| format!("{:x}", n))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
@@ -0,0 +1,1067 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use proto::caps::Capability;
+use proto::command::Command;
+use proto::error::ProtocolError;
+use proto::message::{Message, MessageContents, Tag};
+use proto::prefix::Prefix;
+use proto::response::Response;
+use tokio::sync::RwLock;
+
+use crate::channel::{Channel, Rank};
+use crate::client::{Client, UserMode};
+use crate::config::Privilege;
+use crate::server::{casefold, secrets_match, ServerState, KICKLEN, MONITOR_LIMIT, SASL_MECHANISMS};
+
+/// Collects the replies to a single command so they can be correlated with
+/// the command's `label` tag once it has been handled.
+#[derive(Debug)]
+pub struct Responder {
+    prefix: Prefix,
+    label: Option<String>,
+    replies: Vec<Message>,
+}
+
+impl Responder {
+    /// The label is only captured if the client negotiated `labeled-response`.
+    pub fn new(server: &ServerState, client: &Client, message: &Message) -> Responder {
+        let label = if client.state().has_cap(Capability::LabeledResponse) {
+            message.tag("label").filter(|l| !l.is_empty()).map(|l| l.to_owned())
+        } else {
+            None
+        };
+        Responder {
+            prefix: server.get_name().into(),
+            label,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Queues a reply. Replies without a prefix are sent from the server.
+    pub fn send<M: Into<Message>>(&mut self, msg: M) {
+        let mut msg: Message = msg.into();
+        if msg.prefix.is_none() {
+            msg.prefix = Some(self.prefix.clone());
+        }
+        self.replies.push(msg);
+    }
+
+    /// Sends the queued replies. A labeled command gets an `ACK` if there
+    /// were no replies, a labeled reply if there was one, and otherwise a
+    /// `labeled-response` batch.
+    pub fn finish(self, client: &Client) -> Result<(), ProtocolError> {
+        let sender = client.sender();
+        let label = match self.label {
+            Some(label) => label,
+            None => {
+                for reply in self.replies {
+                    sender.send(reply)?;
+                }
+                return Ok(());
+            }
+        };
+        let mut replies = self.replies;
+        match replies.len() {
+            0 => {
+                let mut ack: Message = Command::ACK.into();
+                ack.prefix = Some(self.prefix);
+                ack.set_tag(Tag::new("label", Some(&label)));
+                sender.send(ack)
+            }
+            1 => {
+                let mut reply = replies.remove(0);
+                reply.set_tag(Tag::new("label", Some(&label)));
+                sender.send(reply)
+            }
+            _ => {
+                let batch = if client.state().has_cap(Capability::Batch) {
+                    sender.batch_tagged(
+                        Some(self.prefix),
+                        vec![Tag::new("label", Some(&label))],
+                        "labeled-response",
+                        Vec::new(),
+                    )?
+                } else {
+                    sender.unbatched(Some(self.prefix))
+                };
+                for reply in replies {
+                    batch.send(reply)?;
+                }
+                batch.end()
+            }
+        }
+    }
+}
+
+/// Handles a message from a registered client.
+pub async fn dispatch(
+    server: &Arc<RwLock<ServerState>>,
+    client: &Arc<RwLock<Client>>,
+    message: Message,
+) -> Result<(), ProtocolError> {
+    let state = server.read().await;
+    let mut responder = Responder::new(&state, &*client.read().await, &message);
+    if let MessageContents::Command(command) = &message.contents {
+        match command {
+            Command::CAP(_, sub, arg, _) => {
+                let mut client = client.write().await;
+                state.handle_cap(&mut client, &mut responder, sub, arg.as_deref());
+            }
+            Command::PASS(_) | Command::USER(_, _, _, _) => {
+                let nick = client.read().await.state().nick().to_owned();
+                responder.send(Response::ErrAlreadyRegistred(nick));
+            }
+            Command::NICK(nick, _) => change_nick(&state, client, &mut responder, nick).await,
+            Command::MONITOR(sub, targets) => {
+                monitor(&state, client, &mut responder, sub, targets.as_deref()).await
+            }
+            Command::JOIN(channels, keys, _) => {
+                join(&state, client, &mut responder, channels, keys.as_deref()).await
+            }
+            Command::MODE(target, modes, params) => {
+                if Channel::valid_name(target) {
+                    let params = params.clone().unwrap_or_default();
+                    channel_mode(&state, client, &mut responder, target, modes.as_deref(), &params).await
+                } else {
+                    user_mode(&state, client, &mut responder, target, modes.as_deref()).await
+                }
+            }
+            Command::INVITE(target, channel) => {
+                invite(&state, client, &mut responder, target, channel).await
+            }
+            Command::KICK(channels, users, reason) => {
+                kick(&state, client, &mut responder, channels, users, reason.as_deref()).await
+            }
+            Command::PART(channels, reason) => {
+                part(&state, client, &mut responder, channels, reason.as_deref()).await
+            }
+            Command::PRIVMSG(target, text, ccs) => {
+                let tags = state.relayed_tags(&message);
+                let targets = std::iter::once(target).chain(ccs.iter().flatten());
+                for target in targets {
+                    let msg = Command::PRIVMSG(target.clone(), text.clone(), None);
+                    deliver(&state, client, &mut responder, target, msg, tags.clone(), true).await;
+                }
+            }
+            Command::NOTICE(target, text) => {
+                let tags = state.relayed_tags(&message);
+                let msg = Command::NOTICE(target.clone(), text.clone());
+                deliver(&state, client, &mut responder, target, msg, tags, false).await;
+            }
+            Command::TAGMSG(target) => {
+                let tags = state.relayed_tags(&message);
+                let msg = Command::TAGMSG(target.clone());
+                deliver(&state, client, &mut responder, target, msg, tags, true).await;
+            }
+            Command::NAMES(channels) => {
+                let client = client.read().await;
+                match channels {
+                    Some(channels) => {
+                        for name in channels.split(',') {
+                            names(&state, &client, &mut responder, name).await;
+                        }
+                    }
+                    None => responder.send(Response::RplEndOfNames(
+                        client.state().nick().to_owned(),
+                        "*".to_owned(),
+                    )),
+                }
+            }
+            Command::WHO(mask, _) => {
+                let client = client.read().await;
+                who(&state, &client, &mut responder, mask.as_deref().unwrap_or("*")).await;
+            }
+            Command::WHOIS(_, nicks) => {
+                let client = client.read().await;
+                whois(&state, &client, &mut responder, nicks).await;
+            }
+            Command::OPER(name, password) => oper(&state, client, &mut responder, name, password).await,
+            Command::REHASH => rehash(&state, client, &mut responder).await,
+            Command::AWAY(away) => set_away(&state, client, &mut responder, away.clone()).await,
+            Command::SETNAME(realname) => {
+                set_realname(&state, client, &mut responder, realname.clone()).await
+            }
+            Command::AUTHENTICATE(data) => {
+                let account = authenticate(&state, &mut *client.write().await, &mut responder, data);
+                if account.is_some() {
+                    set_account(&state, client, account).await;
+                }
+            }
+            Command::PING(_, _) | Command::PONG(_, _) => {}
+            _ => {}
+        }
+    }
+    let client = client.read().await;
+    responder.finish(&client)
+}
+
+/// Joins `items` with `sep` into lines which stay comfortably inside the
+/// 512 byte line limit once the rest of the reply is added.
+fn join_limited(items: Vec<String>, sep: char) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for item in items {
+        if !line.is_empty() && line.len() + item.len() > 400 {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(sep);
+        }
+        line.push_str(&item);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+async fn change_nick(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    new: &str,
+) {
+    let (old, monitoring) = {
+        let client = client.read().await;
+        (client.state().nick().to_owned(), client.state().monitoring().clone())
+    };
+    if old == new {
+        return;
+    }
+    if let Err(e) = state.change_nick(&old, new, &monitoring).await {
+        responder.send(e);
+        return;
+    }
+    let mut client = client.write().await;
+    let old_prefix = client.prefix();
+    client.set_nick(new.to_owned());
+    let client = client.downgrade();
+    let mut msg: Message = Command::NICK(new.to_owned(), None).into();
+    msg.prefix = Some(old_prefix);
+    let others = state.common_nicks(new).await;
+    state.send_to_nicks(others.iter(), None, msg.clone()).await;
+    responder.send(msg);
+    if casefold(&old) != casefold(new) {
+        state.notify_monitors(&old, None).await;
+    }
+    state.notify_monitors(new, Some(client.prefix())).await;
+}
+
+/// Sends RPL_MONONLINE and RPL_MONOFFLINE for `targets`.
+async fn monitor_status(state: &ServerState, responder: &mut Responder, nick: &str, targets: &[String]) {
+    let mut online = Vec::new();
+    let mut offline = Vec::new();
+    for target in targets {
+        match state.get_client(target).await {
+            Some(other) => online.push(other.read().await.prefix().to_string()),
+            None => offline.push(target.clone()),
+        }
+    }
+    for line in join_limited(online, ',') {
+        responder.send(Response::RplMonOnline(nick.to_owned(), line));
+    }
+    for line in join_limited(offline, ',') {
+        responder.send(Response::RplMonOffline(nick.to_owned(), line));
+    }
+}
+
+async fn monitor(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    sub: &str,
+    targets: Option<&str>,
+) {
+    let targets: Vec<String> = targets
+        .unwrap_or("")
+        .split(',')
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_owned())
+        .collect();
+    let mut client = client.write().await;
+    let nick = client.state().nick().to_owned();
+    match sub {
+        "+" => {
+            let mut added = Vec::new();
+            for (i, target) in targets.iter().enumerate() {
+                if client.state().monitoring().len() >= MONITOR_LIMIT {
+                    responder.send(Response::ErrMonListFull(nick.clone(), MONITOR_LIMIT, targets[i..].join(",")));
+                    break;
+                }
+                if client.add_monitor(casefold(target)) {
+                    state.add_monitor(&nick, target).await;
+                }
+                added.push(target.clone());
+            }
+            drop(client);
+            monitor_status(state, responder, &nick, &added).await;
+        }
+        "-" => {
+            for target in targets.iter() {
+                if client.remove_monitor(&casefold(target)) {
+                    state.remove_monitor(&nick, target).await;
+                }
+            }
+        }
+        "C" | "c" => {
+            for target in client.clear_monitors() {
+                state.remove_monitor(&nick, &target).await;
+            }
+        }
+        "L" | "l" => {
+            let monitoring: Vec<String> = client.state().monitoring().iter().cloned().collect();
+            for line in join_limited(monitoring, ',') {
+                responder.send(Response::RplMonList(nick.clone(), line));
+            }
+            responder.send(Response::RplEndOfMonList(nick));
+        }
+        "S" | "s" => {
+            let monitoring: Vec<String> = client.state().monitoring().iter().cloned().collect();
+            drop(client);
+            monitor_status(state, responder, &nick, &monitoring).await;
+        }
+        _ => (),
+    }
+}
+
+async fn join(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    channels: &str,
+    keys: Option<&str>,
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    let mut keys = keys.unwrap_or("").split(',');
+    for name in channels.split(',') {
+        let key = keys.next().filter(|k| !k.is_empty());
+        if !Channel::valid_name(name) {
+            responder.send(Response::ErrNoSuchChannel(nick.clone(), name.to_owned()));
+            continue;
+        }
+        let (name, members) = match state.join_channel(name, &client, key).await {
+            Ok(Some(joined)) => joined,
+            Ok(None) => continue,
+            Err(e) => {
+                responder.send(e);
+                continue;
+            }
+        };
+        let prefix = client.prefix();
+        let mut join: Message = Command::JOIN(name.clone(), None, None).into();
+        join.prefix = Some(prefix.clone());
+        let mut extended: Message = Command::JOIN(
+            name.clone(),
+            Some(client.state().account().unwrap_or("*").to_owned()),
+            Some(client.state().realname().to_owned()),
+        )
+        .into();
+        extended.prefix = Some(prefix.clone());
+        let others: Vec<&String> = members.iter().filter(|n| **n != nick).collect();
+        state
+            .send_to_nicks_by_cap(
+                others.iter().copied(),
+                Capability::ExtendedJoin,
+                extended.clone(),
+                Some(join.clone()),
+            )
+            .await;
+        if let Some(away) = client.state().away() {
+            let mut msg: Message = Command::AWAY(Some(away.to_owned())).into();
+            msg.prefix = Some(prefix);
+            state
+                .send_to_nicks(others.iter().copied(), Some(Capability::AwayNotify), msg)
+                .await;
+        }
+        if client.state().has_cap(Capability::ExtendedJoin) {
+            responder.send(extended);
+        } else {
+            responder.send(join);
+        }
+        names(state, &client, responder, &name).await;
+    }
+}
+
+async fn channel_mode(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    name: &str,
+    modes: Option<&str>,
+    params: &[String],
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    let modes = match modes {
+        Some(modes) => modes,
+        None => {
+            match state.get_channel(name).await {
+                Some(channel) => responder.send(Response::RplChannelModeIs(
+                    nick,
+                    channel.name().to_owned(),
+                    channel.modes().to_string(),
+                )),
+                None => responder.send(Response::ErrNoSuchChannel(nick, name.to_owned())),
+            }
+            return;
+        }
+    };
+    // Anyone may see the ban list, so it's listed before modes are checked.
+    if modes.trim_start_matches('+') == "b" && params.is_empty() {
+        match state.get_channel(name).await {
+            Some(channel) => {
+                for ban in &channel.modes().bans {
+                    responder.send(Response::RplBanList(
+                        nick.clone(),
+                        channel.name().to_owned(),
+                        ban.mask.clone(),
+                        ban.setter.clone(),
+                        ban.set_at,
+                    ));
+                }
+                responder.send(Response::RplEndOfBanList(nick, channel.name().to_owned()));
+            }
+            None => responder.send(Response::ErrNoSuchChannel(nick, name.to_owned())),
+        }
+        return;
+    }
+    match state.change_channel_modes(name, &nick, modes, params).await {
+        Ok((name, members, change)) => {
+            for error in change.errors {
+                responder.send(error);
+            }
+            if change.modes.is_empty() {
+                return;
+            }
+            let mut msg: Message = Command::MODE(name, Some(change.modes), Some(change.params)).into();
+            msg.prefix = Some(client.prefix());
+            state
+                .send_to_nicks(members.iter().filter(|n| **n != nick), None, msg.clone())
+                .await;
+            responder.send(msg);
+        }
+        Err(e) => responder.send(e),
+    }
+}
+
+/// Shows or changes the modes of the client itself. Modes the user may not
+/// change are silently ignored. Toggling `+x` changes the displayed host.
+async fn user_mode(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    target: &str,
+    modes: Option<&str>,
+) {
+    let mut client = client.write().await;
+    let nick = client.state().nick().to_owned();
+    if casefold(target) != casefold(&nick) {
+        drop(client);
+        match state.get_client(target).await {
+            Some(_) => responder.send(Response::ErrUsersDontMatch(nick)),
+            None => responder.send(Response::ErrNoSuchNick(nick, target.to_owned())),
+        }
+        return;
+    }
+    let modes = match modes {
+        Some(modes) => modes,
+        None => {
+            responder.send(Response::RplUModeIs(nick, UserMode::to_string(client.state().modes())));
+            return;
+        }
+    };
+    let old_prefix = client.prefix();
+    let old_host = client.host();
+    let mut adding = true;
+    let mut unknown = false;
+    let mut changes = String::new();
+    let mut direction = None;
+    for c in modes.chars() {
+        let mode = match c {
+            '+' | '-' => {
+                adding = c == '+';
+                continue;
+            }
+            c => match UserMode::from_char(c) {
+                Some(mode) => mode,
+                None => {
+                    unknown = true;
+                    continue;
+                }
+            },
+        };
+        let allowed = if adding {
+            mode.user_settable()
+                && (mode != UserMode::ServerNotices || client.state().has_mode(UserMode::Oper))
+                && (mode != UserMode::Cloaked || client.state().cloak().is_some())
+        } else {
+            mode.user_removable()
+        };
+        if !allowed || !client.set_mode(mode, adding) {
+            continue;
+        }
+        if direction != Some(adding) {
+            changes.push(if adding { '+' } else { '-' });
+            direction = Some(adding);
+        }
+        changes.push(mode.mode());
+    }
+    if !client.state().has_mode(UserMode::Oper) {
+        client.set_privileges(HashSet::new());
+    }
+    let client = client.downgrade();
+    if unknown {
+        responder.send(Response::ErrUModeUnknownFlag(nick.clone()));
+    }
+    if !changes.is_empty() {
+        let mut msg: Message = Command::MODE(nick.clone(), Some(changes), None).into();
+        msg.prefix = Some(client.prefix());
+        responder.send(msg);
+    }
+    // Only the cloak is announced; a client dropping it knows its real host.
+    if client.host() != old_host && client.state().has_mode(UserMode::Cloaked) {
+        responder.send(Response::RplHostHidden(nick, client.host()));
+    }
+    let _ = change_host(state, &client, old_prefix).await;
+}
+
+/// Grants operator status and the privileges of the named oper block.
+async fn oper(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    name: &str,
+    password: &str,
+) {
+    let mut client = client.write().await;
+    let nick = client.state().nick().to_owned();
+    let oper = match state.find_oper(name) {
+        Some(oper) => oper,
+        None => {
+            responder.send(Response::ErrNoOperHost(nick));
+            return;
+        }
+    };
+    if let Some(fingerprint) = &oper.certfp {
+        if !client.state().certfp().is_some_and(|fp| fp.matches(fingerprint)) {
+            responder.send(Response::ErrNoOperHost(nick));
+            return;
+        }
+    }
+    // An empty password means the certificate alone is enough.
+    let certificate_only = oper.password.is_empty() && oper.certfp.is_some();
+    if !certificate_only && !secrets_match(&oper.password, password) {
+        responder.send(Response::ErrPasswdMismatch(nick));
+        return;
+    }
+    client.set_privileges(oper.privileges.iter().copied().collect());
+    responder.send(Response::RplYoureOper(nick.clone()));
+    if client.set_mode(UserMode::Oper, true) {
+        let mut msg: Message = Command::MODE(nick, Some("+o".to_owned()), None).into();
+        msg.prefix = Some(client.prefix());
+        responder.send(msg);
+    }
+}
+
+/// Asks the server to reload its config. The operator is told how it went
+/// once it's done.
+async fn rehash(state: &ServerState, client: &Arc<RwLock<Client>>, responder: &mut Responder) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    if !client.state().has_privilege(Privilege::Rehash) {
+        responder.send(Response::ErrNoPrivileges(nick));
+        return;
+    }
+    let sender = client.sender();
+    drop(client);
+    responder.send(Response::RplRehashing(nick.clone(), state.config_file().to_owned()));
+    state.notice_opers(&format!("{} is rehashing the server config", nick)).await;
+    state.request_rehash(nick, sender);
+}
+
+/// Invites a user to a channel, telling channel operators which negotiated
+/// `invite-notify` as well as the invited user.
+async fn invite(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    target: &str,
+    channel: &str,
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    let recipient = match state.get_client(target).await {
+        Some(recipient) => recipient,
+        None => {
+            responder.send(Response::ErrNoSuchNick(nick, target.to_owned()));
+            return;
+        }
+    };
+    let recipient = recipient.read().await;
+    let target = recipient.state().nick().to_owned();
+    match state.invite(channel, &nick, &target).await {
+        Ok((channel, operators)) => {
+            let mut msg: Message = Command::INVITE(target.clone(), channel.clone()).into();
+            msg.prefix = Some(client.prefix());
+            let _ = recipient.sender().send(msg.clone());
+            drop(recipient);
+            state
+                .send_to_nicks(
+                    operators.iter().filter(|o| **o != target),
+                    Some(Capability::InviteNotify),
+                    msg,
+                )
+                .await;
+            responder.send(Response::RplInviting(nick, target, channel));
+        }
+        Err(e) => responder.send(e),
+    }
+}
+
+/// Kicks users from channels. Either one channel and several users or the
+/// same number of each may be given.
+async fn kick(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    channels: &str,
+    users: &str,
+    reason: Option<&str>,
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    let channels: Vec<&str> = channels.split(',').collect();
+    let users: Vec<&str> = users.split(',').collect();
+    if channels.len() != 1 && channels.len() != users.len() {
+        responder.send(Response::ErrNeedMoreParams("KICK".to_owned()));
+        return;
+    }
+    let mut reason = reason.unwrap_or(&nick).to_owned();
+    if reason.len() > KICKLEN {
+        let mut end = KICKLEN;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    for (i, user) in users.iter().enumerate() {
+        let channel = if channels.len() == 1 { channels[0] } else { channels[i] };
+        match state.kick(channel, &nick, user).await {
+            Ok((channel, target, members)) => {
+                let mut msg: Message = Command::KICK(channel, target, Some(reason.clone())).into();
+                msg.prefix = Some(client.prefix());
+                state
+                    .send_to_nicks(members.iter().filter(|n| **n != nick), None, msg.clone())
+                    .await;
+                responder.send(msg);
+            }
+            Err(e) => responder.send(e),
+        }
+    }
+}
+
+/// Sends RPL_NAMREPLY for a channel, honouring `multi-prefix` and
+/// `userhost-in-names`, followed by RPL_ENDOFNAMES.
+async fn names(state: &ServerState, client: &Client, responder: &mut Responder, name: &str) {
+    let nick = client.state().nick().to_owned();
+    let multi = client.state().has_cap(Capability::MultiPrefix);
+    let userhost = client.state().has_cap(Capability::UserhostInNames);
+    if let Some(channel) = state.get_channel(name).await {
+        let joined = channel.is_member(&nick);
+        let mut entries = Vec::new();
+        for member in channel.members() {
+            let prefix = channel
+                .ranks(member)
+                .map(|r| Rank::prefixes(r, multi))
+                .unwrap_or_default();
+            // Our own entry would otherwise take our own lock twice.
+            let other_lock;
+            let other_guard;
+            let other = if casefold(member) == casefold(&nick) {
+                client
+            } else {
+                other_lock = match state.get_client(member).await {
+                    Some(other) => other,
+                    None => continue,
+                };
+                other_guard = other_lock.read().await;
+                &*other_guard
+            };
+            if !joined && other.state().has_mode(UserMode::Invisible) {
+                continue;
+            }
+            let display = if userhost { other.prefix().to_string() } else { member.clone() };
+            entries.push(format!("{}{}", prefix, display));
+        }
+        for line in join_limited(entries, ' ') {
+            responder.send(Response::RplNamReply(nick.clone(), "=".to_owned(), channel.name().to_owned(), line));
+        }
+    }
+    responder.send(Response::RplEndOfNames(nick, name.to_owned()));
+}
+
+/// Sends RPL_WHOREPLY for the members of a channel or a single nick.
+async fn who(state: &ServerState, client: &Client, responder: &mut Responder, mask: &str) {
+    let nick = client.state().nick().to_owned();
+    let multi = client.state().has_cap(Capability::MultiPrefix);
+    let (channel, targets): (Option<Channel>, Vec<String>) = if Channel::valid_name(mask) {
+        match state.get_channel(mask).await {
+            Some(channel) => {
+                let members = channel.members().cloned().collect();
+                (Some(channel), members)
+            }
+            None => (None, Vec::new()),
+        }
+    } else {
+        (None, vec![mask.to_owned()])
+    };
+    // Invisible users are only listed to clients sharing the channel, but a
+    // WHO for a nick always finds them.
+    let joined = channel.as_ref().is_none_or(|c| c.is_member(&nick));
+    for target in targets {
+        // Our own entry would otherwise take our own lock twice.
+        let other_lock;
+        let other_guard;
+        let other = if casefold(&target) == casefold(&nick) {
+            client
+        } else {
+            other_lock = match state.get_client(&target).await {
+                Some(other) => other,
+                None => continue,
+            };
+            other_guard = other_lock.read().await;
+            &*other_guard
+        };
+        if other.state().has_mode(UserMode::Invisible) && !joined {
+            continue;
+        }
+        let mut flags = if other.state().away().is_some() { "G" } else { "H" }.to_owned();
+        if other.state().has_mode(UserMode::Oper) {
+            flags.push('*');
+        }
+        if other.state().has_mode(UserMode::Bot) {
+            flags.push('B');
+        }
+        if let Some(ranks) = channel.as_ref().and_then(|c| c.ranks(&target)) {
+            flags.push_str(&Rank::prefixes(ranks, multi));
+        }
+        responder.send(Response::RplWhoReply(
+            nick.clone(),
+            channel.as_ref().map_or("*", |c| c.name()).to_owned(),
+            other.state().user().to_owned(),
+            other.host(),
+            state.get_name().to_owned(),
+            other.state().nick().to_owned(),
+            flags,
+            other.state().realname().to_owned(),
+        ));
+    }
+    responder.send(Response::RplEndOfWho(nick, mask.to_owned()));
+}
+
+/// Sends the WHOIS replies for each of `nicks`. The real host is only shown
+/// to the user themselves and to operators with `auspex`.
+async fn whois(state: &ServerState, client: &Client, responder: &mut Responder, nicks: &str) {
+    let nick = client.state().nick().to_owned();
+    let auspex = client.state().has_privilege(Privilege::Auspex);
+    for target in nicks.split(',') {
+        let other = match state.get_client(target).await {
+            Some(other) => other,
+            None => {
+                responder.send(Response::ErrNoSuchNick(nick.clone(), target.to_owned()));
+                continue;
+            }
+        };
+        // WHOIS on ourselves would otherwise take our own lock twice.
+        let other_guard;
+        let other = if casefold(target) == casefold(&nick) {
+            client
+        } else {
+            other_guard = other.read().await;
+            &*other_guard
+        };
+        let target = other.state().nick().to_owned();
+        responder.send(Response::RplWhoisUser(
+            nick.clone(),
+            target.clone(),
+            other.state().user().to_owned(),
+            other.host(),
+            other.state().realname().to_owned(),
+        ));
+        let channels: Vec<String> = state
+            .channels_of(&target)
+            .await
+            .into_iter()
+            .map(|(name, ranks)| format!("{}{}", Rank::prefixes(&ranks, false), name))
+            .collect();
+        for line in join_limited(channels, ' ') {
+            responder.send(Response::RplWhoisChannels(nick.clone(), target.clone(), line));
+        }
+        responder.send(Response::RplWhoisServer(
+            nick.clone(),
+            target.clone(),
+            state.get_name().to_owned(),
+            "cawcaw".to_owned(),
+        ));
+        if let Some(away) = other.state().away() {
+            responder.send(Response::RplAway(nick.clone(), target.clone(), away.to_owned()));
+        }
+        if other.state().has_mode(UserMode::Oper) {
+            responder.send(Response::RplWhoisOperator(nick.clone(), target.clone()));
+        }
+        if let Some(account) = other.state().account() {
+            responder.send(Response::RplWhoisAccount(nick.clone(), target.clone(), account.to_owned()));
+        }
+        if client.state().has_mode(UserMode::Oper) {
+            for reason in other.state().listings() {
+                responder.send(Response::RplWhoisSpecial(nick.clone(), target.clone(), reason.clone()));
+            }
+        }
+        if other.state().has_mode(UserMode::Secure) {
+            responder.send(Response::RplWhoisSecure(nick.clone(), target.clone()));
+        }
+        if let Some(certfp) = other.state().certfp() {
+            if auspex || target == nick {
+                for fp in [&certfp.sha256, &certfp.sha512] {
+                    responder.send(Response::RplWhoisCertFp(nick.clone(), target.clone(), fp.clone()));
+                }
+            }
+        }
+        if auspex || target == nick {
+            responder.send(Response::RplWhoisHost(
+                nick.clone(),
+                target.clone(),
+                other.real_host(),
+                other.address().ip().to_string(),
+            ));
+        }
+        responder.send(Response::RplEndOfWhois(nick.clone(), target));
+    }
+}
+
+async fn part(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    channels: &str,
+    reason: Option<&str>,
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    for name in channels.split(',') {
+        match state.part_channel(name, &nick).await {
+            Ok((name, members)) => {
+                let mut msg: Message =
+                    Command::PART(name, reason.map(|r| r.to_owned())).into();
+                msg.prefix = Some(client.prefix());
+                state
+                    .send_to_nicks(members.iter().filter(|n| **n != nick), None, msg.clone())
+                    .await;
+                responder.send(msg);
+            }
+            Err(e) => responder.send(e),
+        }
+    }
+}
+
+/// Delivers a PRIVMSG, NOTICE or TAGMSG to a channel or nick. Client-only
+/// `tags` are only passed to recipients which negotiated `message-tags`, and
+/// TAGMSG is not delivered to anyone else. NOTICEs never generate automatic
+/// replies.
+async fn deliver(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    target: &str,
+    command: Command,
+    tags: Vec<Tag>,
+    replies: bool,
+) {
+    let client = client.read().await;
+    let nick = client.state().nick().to_owned();
+    let tags_only = matches!(command, Command::TAGMSG(_));
+    let mut plain: Message = command.into();
+    plain.prefix = Some(client.prefix());
+    let mut tagged = plain.clone();
+    if client.state().has_mode(UserMode::Bot) {
+        tagged.set_tag(Tag::new("bot", None));
+    }
+    for tag in tags {
+        tagged.set_tag(tag);
+    }
+    let plain = if tags_only { None } else { Some(plain) };
+    if Channel::valid_name(target) {
+        match state.channel_members(target).await {
+            Some((_, members)) => {
+                state
+                    .send_to_nicks_by_cap(
+                        members.iter().filter(|n| **n != nick),
+                        Capability::MessageTags,
+                        tagged,
+                        plain,
+                    )
+                    .await;
+            }
+            None if replies => {
+                responder.send(Response::ErrNoSuchChannel(nick, target.to_owned()))
+            }
+            None => (),
+        }
+        return;
+    }
+    match state.get_client(target).await {
+        Some(recipient) => {
+            let recipient = recipient.read().await;
+            if recipient.state().has_cap(Capability::MessageTags) {
+                let _ = recipient.sender().send(tagged);
+            } else if let Some(plain) = plain {
+                let _ = recipient.sender().send(plain);
+            }
+            if let (true, false, Some(away)) = (replies, tags_only, recipient.state().away()) {
+                responder.send(Response::RplAway(nick, target.to_owned(), away.to_owned()));
+            }
+        }
+        None if replies => responder.send(Response::ErrNoSuchNick(nick, target.to_owned())),
+        None => (),
+    }
+}
+
+async fn set_away(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    away: Option<String>,
+) {
+    let mut client = client.write().await;
+    client.set_away(away.clone());
+    let client = client.downgrade();
+    let nick = client.state().nick().to_owned();
+    let mut msg: Message = Command::AWAY(away.clone()).into();
+    msg.prefix = Some(client.prefix());
+    state.notify_common(&nick, Capability::AwayNotify, msg).await;
+    match away {
+        Some(_) => responder.send(Response::RplNowAway(nick)),
+        None => responder.send(Response::RplUnAway(nick)),
+    }
+}
+
+async fn set_realname(
+    state: &ServerState,
+    client: &Arc<RwLock<Client>>,
+    responder: &mut Responder,
+    realname: String,
+) {
+    let mut client = client.write().await;
+    client.set_realname(realname.clone());
+    let client = client.downgrade();
+    let mut msg: Message = Command::SETNAME(realname).into();
+    msg.prefix = Some(client.prefix());
+    state
+        .notify_common(client.state().nick(), Capability::SetName, msg.clone())
+        .await;
+    if client.state().has_cap(Capability::SetName) {
+        responder.send(msg);
+    }
+}
+
+/// Takes one step of a SASL exchange, returning the account to log the client
+/// in to once it succeeds. EXTERNAL logs in to the account configured for the
+/// client's certificate, which the client may name to be sure of it.
+pub fn authenticate(state: &ServerState, client: &mut Client, responder: &mut Responder, data: &str) -> Option<String> {
+    let nick = match client.state().nick() {
+        "" => "*".to_owned(),
+        nick => nick.to_owned(),
+    };
+    if !client.state().has_cap(Capability::Sasl) {
+        responder.send(Response::ErrSaslFail(nick));
+        return None;
+    }
+    if client.state().account().is_some() {
+        responder.send(Response::ErrSaslAlready(nick));
+        return None;
+    }
+    if data == "*" {
+        client.set_authenticating(false);
+        responder.send(Response::ErrSaslAborted(nick));
+        return None;
+    }
+    if !client.authenticating() {
+        if data.eq_ignore_ascii_case("EXTERNAL") {
+            client.set_authenticating(true);
+            responder.send(Command::AUTHENTICATE("+".to_owned()));
+        } else {
+            responder.send(Response::RplSaslMechs(nick.clone(), SASL_MECHANISMS.to_owned()));
+            responder.send(Response::ErrSaslFail(nick));
+        }
+        return None;
+    }
+    client.set_authenticating(false);
+    // `+` is an empty authorization identity, leaving the account to us.
+    let authzid = match data {
+        "+" => Some(String::new()),
+        data => STANDARD.decode(data).ok().and_then(|id| String::from_utf8(id).ok()),
+    };
+    let account = client.state().certfp().and_then(|certfp| state.find_account(certfp));
+    match (account, authzid) {
+        (Some(account), Some(authzid)) if authzid.is_empty() || authzid == account => {
+            let user = match client.state().user() {
+                "" => "*".to_owned(),
+                user => user.to_owned(),
+            };
+            let mask = format!("{}!{}@{}", nick, user, client.host());
+            responder.send(Response::RplLoggedIn(nick.clone(), mask, account.clone()));
+            responder.send(Response::RplSaslSuccess(nick));
+            Some(account)
+        }
+        _ => {
+            responder.send(Response::ErrSaslFail(nick));
+            None
+        }
+    }
+}
+
+/// Changes the account a client is logged in to, notifying clients in common
+/// channels which negotiated `account-notify` and updating the client's `+r`.
+pub async fn set_account(state: &ServerState, client: &Arc<RwLock<Client>>, account: Option<String>) {
+    let mut client = client.write().await;
+    client.set_account(account.clone());
+    let logged_in = account.is_some();
+    let changed = client.set_mode(UserMode::Registered, logged_in);
+    let client = client.downgrade();
+    if changed {
+        let nick = client.state().nick().to_owned();
+        let modes = if logged_in { "+r" } else { "-r" };
+        let mut msg: Message = Command::MODE(nick, Some(modes.to_owned()), None).into();
+        msg.prefix = Some(client.prefix());
+        let _ = client.sender().send(msg);
+    }
+    let mut msg: Message = Command::ACCOUNT(account.unwrap_or_else(|| "*".to_owned())).into();
+    msg.prefix = Some(client.prefix());
+    state
+        .notify_common(client.state().nick(), Capability::AccountNotify, msg)
+        .await;
+}
+
+/// Tells clients in common channels (and the client itself) which negotiated
+/// `chghost` that the client's username or displayed host changed from those
+/// in `old`. Does nothing if neither did.
+pub async fn change_host(state: &ServerState, client: &Client, old: Prefix) -> Result<(), ProtocolError> {
+    let (user, host) = (client.state().user().to_owned(), client.host());
+    if matches!(&old, Prefix::Nickname(_, u, h) if *u == user && *h == host) {
+        return Ok(());
+    }
+    let mut msg: Message = Command::CHGHOST(user, host).into();
+    msg.prefix = Some(old);
+    state
+        .notify_common(client.state().nick(), Capability::ChgHost, msg.clone())
+        .await;
+    if client.state().has_cap(Capability::ChgHost) {
+        client.sender().send(msg)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpSocket;
+
+use crate::config::Ident;
+
+/// Longest reply line accepted from an identd, per RFC 1413.
+const MAX_REPLY_LENGTH: u64 = 1000;
+
+/// Queries the identd of connecting clients (RFC 1413).
+#[derive(Debug, Clone)]
+pub struct IdentClient {
+    enabled: bool,
+    timeout: Duration,
+    port: u16,
+}
+
+impl IdentClient {
+    pub fn new(config: &Ident) -> IdentClient {
+        IdentClient {
+            enabled: config.enabled,
+            timeout: Duration::from_secs(config.timeout),
+            port: config.port,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Asks the identd on the client's host who owns the connection from
+    /// `peer` to `local`. Returns None if it doesn't answer in time, reports
+    /// an error or returns a username which can't be used.
+    pub async fn lookup(&self, peer: SocketAddr, local: SocketAddr) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        tokio::time::timeout(self.timeout, self.query(peer, local))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn query(&self, peer: SocketAddr, local: SocketAddr) -> Option<String> {
+        // identd matches the connection by both addresses, so ask from the
+        // one the client connected to, which may not be the default route's.
+        let local_ip = local.ip().to_canonical();
+        let socket = match local_ip {
+            IpAddr::V4(_) => TcpSocket::new_v4(),
+            IpAddr::V6(_) => TcpSocket::new_v6(),
+        }
+        .ok()?;
+        socket.bind(SocketAddr::new(local_ip, 0)).ok()?;
+        let mut stream = socket.connect(SocketAddr::new(peer.ip().to_canonical(), self.port)).await.ok()?;
+        let request = format!("{}, {}\r\n", peer.port(), local.port());
+        stream.write_all(request.as_bytes()).await.ok()?;
+        let mut reply = String::new();
+        BufReader::new(stream.take(MAX_REPLY_LENGTH))
+            .read_line(&mut reply)
+            .await
+            .ok()?;
+        parse_reply(&reply, peer.port(), local.port())
+    }
+}
+
+/// Parses `<port>, <port> : USERID : <os> : <username>`, checking that the
+/// ports are the ones asked about.
+fn parse_reply(reply: &str, peer_port: u16, local_port: u16) -> Option<String> {
+    let mut fields = reply.trim_end_matches(['\r', '\n']).splitn(4, ':');
+    let (ports, kind, _os, user) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    let (remote, local) = ports.split_once(',')?;
+    if remote.trim().parse::<u16>().ok()? != peer_port
+        || local.trim().parse::<u16>().ok()? != local_port
+        || kind.trim() != "USERID"
+    {
+        return None;
+    }
+    let user = user.trim();
+    if user.is_empty()
+        || !user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return None;
+    }
+    Some(user.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_userid() {
+        assert_eq!(parse_reply("6193, 23 : USERID : UNIX : stjohns\r\n", 6193, 23), Some("stjohns".to_owned()));
+    }
+
+    #[test]
+    fn rejects_error() {
+        assert_eq!(parse_reply("6195, 23 : ERROR : NO-USER\r\n", 6195, 23), None);
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(parse_reply("garbage\r\n", 6193, 23), None);
+        assert_eq!(parse_reply("6193 23 : USERID : UNIX : stjohns\r\n", 6193, 23), None);
+        assert_eq!(parse_reply("6193, 23 : USERID : UNIX : st johns\r\n", 6193, 23), None);
+        assert_eq!(parse_reply("6193, 23 : USERID : UNIX : \r\n", 6193, 23), None);
+    }
+
+    #[test]
+    fn rejects_other_ports() {
+        assert_eq!(parse_reply("6194, 23 : USERID : UNIX : stjohns\r\n", 6193, 23), None);
+        assert_eq!(parse_reply("6193, 24 : USERID : UNIX : stjohns\r\n", 6193, 23), None);
+    }
+
+    fn client(port: u16) -> IdentClient {
+        IdentClient::new(&Ident {
+            enabled: true,
+            timeout: 1,
+            port,
+        })
+    }
+
+    #[tokio::test]
+    async fn looks_up_username() {
+        let identd = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = identd.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, from) = identd.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request = String::new();
+            stream.read_line(&mut request).await.unwrap();
+            stream.get_mut().write_all(b"5555, 6667 : USERID : UNIX : alice\r\n").await.unwrap();
+            (from.ip(), request)
+        });
+        // The client connected to 127.0.0.2, so that's where the query comes from.
+        let peer = "127.0.0.1:5555".parse().unwrap();
+        let local = "127.0.0.2:6667".parse().unwrap();
+        assert_eq!(client(port).lookup(peer, local).await, Some("alice".to_owned()));
+        let (from, request) = server.await.unwrap();
+        assert_eq!(from, "127.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(request, "5555, 6667\r\n");
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let identd = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = identd.local_addr().unwrap().port();
+        // Accept the connection but never answer.
+        let server = tokio::spawn(async move { identd.accept().await.unwrap() });
+        let peer = "127.0.0.1:5555".parse().unwrap();
+        let local = "127.0.0.1:6667".parse().unwrap();
+        let started = std::time::Instant::now();
+        assert_eq!(client(port).lookup(peer, local).await, None);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        drop(server);
+    }
+}
@@ -0,0 +1,59 @@
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Writes log messages to stderr, or to syslog once the server has gone into
+/// the background.
+struct Logger {
+    syslog: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    syslog: AtomicBool::new(false),
+};
+
+/// Logs messages at `level` and above from now on.
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(level);
+}
+
+/// Sends log messages to syslog instead of stderr, which is about to close.
+pub fn use_syslog() {
+    // SAFETY: the identifier is a static string, which openlog may keep.
+    unsafe { libc::openlog(c"cawcaw".as_ptr(), libc::LOG_PID | libc::LOG_NDELAY, libc::LOG_DAEMON) };
+    LOGGER.syslog.store(true, Ordering::Relaxed);
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies are only heard from when something goes wrong.
+        metadata.level() <= log::max_level()
+            && (metadata.target().starts_with("cawcaw") || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.syslog.load(Ordering::Relaxed) {
+            let priority = match record.level() {
+                Level::Error => libc::LOG_ERR,
+                Level::Warn => libc::LOG_WARNING,
+                Level::Info => libc::LOG_INFO,
+                Level::Debug | Level::Trace => libc::LOG_DEBUG,
+            };
+            let message = CString::new(record.args().to_string().replace('\0', ""))
+                .expect("NULs were removed");
+            // SAFETY: both strings are NUL terminated, and the message is
+            // passed as an argument so any % in it isn't interpreted.
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+        } else {
+            let _ = writeln!(io::stderr().lock(), "{:<5} {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
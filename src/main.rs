@@ -1,43 +1,219 @@
 use client::Client;
 use config::Config;
-use server::Server;
-use std::fs::read;
-use std::path::Path;
-use tokio_native_tls::native_tls::Identity;
-use tokio_native_tls::TlsAcceptor;
+use daemon::Ready;
+use getopts::{Matches, Options};
+use log::{debug, error, LevelFilter};
+use server::{Server, ServerError};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+mod certfp;
+mod channel;
+mod class;
 mod client;
+mod cloak;
 mod config;
-mod connection;
+mod daemon;
+mod dns;
+mod dnsbl;
+mod handler;
+mod ident;
+mod logger;
+mod proxy;
 mod server;
+mod throttle;
+mod tls;
 mod tls_socket;
+mod websocket;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let conf = Config::new(Path::new("config.toml")).expect("Failed to read config");
-    println!("{:?}", conf);
-    let mut server = Server::new(conf.server.name).await?;
-    for listener in conf.server.listeners {
-        if let Some(tls) = listener.tls {
-            let cert = read(&tls.cert)
-                .expect(format!("Failed to read TLS certificate {}", &tls.cert).as_ref());
-            let key =
-                read(&tls.key).expect(format!("Failed to read TLS key {}", &tls.key).as_ref());
-            let ident = Identity::from_pkcs8(&cert, &key)
-                .expect("Failed to construct certificate identity");
-            let acceptor = TlsAcceptor::from(
-                tokio_native_tls::native_tls::TlsAcceptor::builder(ident).build()?,
-            );
-            server
-                .add_tls_listener(listener.address, acceptor)
-                .await
-                .expect("Failed to create TLS listener");
-        } else {
-            server
-                .add_listener(listener.address)
-                .await
-                .expect("Failed to create plain listener");
+/// How the tokio runtime runs the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Runtime {
+    /// Everything on the main thread.
+    CurrentThread,
+    /// A pool of worker threads, one per CPU if the count isn't given.
+    MultiThread(Option<usize>),
+}
+
+/// What the command line asked for.
+#[derive(Debug)]
+struct Args {
+    config: PathBuf,
+    check_config: bool,
+    foreground: bool,
+    pid_file: Option<PathBuf>,
+    log_level: LevelFilter,
+    runtime: Runtime,
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "read the config from FILE (default config.toml)", "FILE")
+        .optflag("", "check-config", "check the config and its certificates, then exit")
+        .optflag("f", "foreground", "stay in the foreground instead of daemonizing")
+        .optopt("p", "pid-file", "write the server's process ID to FILE", "FILE")
+        .optopt("l", "log-level", "off, error, warn, info (default), debug or trace", "LEVEL")
+        .optopt("", "runtime", "current-thread (default) or multi-thread", "FLAVOUR")
+        .optopt("w", "workers", "worker threads of the multi-thread runtime", "N")
+        .optflag("V", "version", "print the version and exit")
+        .optflag("h", "help", "print this help and exit");
+    opts
+}
+
+impl Args {
+    fn new(matches: &Matches) -> Result<Args, String> {
+        // A lone path is taken as the config, as in `cawcaw --check-config path`.
+        let config = match (matches.opt_str("config"), matches.free.as_slice()) {
+            (None, []) => PathBuf::from("config.toml"),
+            (Some(path), []) => PathBuf::from(path),
+            (None, [path]) => PathBuf::from(path),
+            _ => return Err("expected at most one config file".to_owned()),
+        };
+        let log_level = match matches.opt_str("log-level") {
+            Some(level) => level.parse().map_err(|_| format!("unknown log level `{}`", level))?,
+            None => LevelFilter::Info,
+        };
+        let workers = match matches.opt_str("workers") {
+            Some(n) => match n.parse() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err(format!("`{}` is not a number of worker threads", n)),
+            },
+            None => None,
+        };
+        let runtime = match (matches.opt_str("runtime").as_deref(), workers) {
+            (None | Some("multi-thread"), Some(n)) => Runtime::MultiThread(Some(n)),
+            (Some("multi-thread"), None) => Runtime::MultiThread(None),
+            (None | Some("current-thread"), None) => Runtime::CurrentThread,
+            (Some("current-thread"), Some(_)) => {
+                return Err("--workers needs the multi-thread runtime".to_owned())
+            }
+            (Some(flavour), _) => return Err(format!("unknown runtime `{}`", flavour)),
+        };
+        Ok(Args {
+            config,
+            check_config: matches.opt_present("check-config"),
+            foreground: matches.opt_present("foreground"),
+            pid_file: matches.opt_str("pid-file").map(PathBuf::from),
+            log_level,
+            runtime,
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let opts = options();
+    let matches = match opts.parse(env::args().skip(1)) {
+        Ok(matches) => matches,
+        Err(e) => return usage_error(&opts, e),
+    };
+    if matches.opt_present("help") {
+        print!("{}", opts.usage(&opts.short_usage("cawcaw")));
+        return ExitCode::SUCCESS;
+    }
+    if matches.opt_present("version") {
+        println!("cawcaw {}", env!("CARGO_PKG_VERSION"));
+        return ExitCode::SUCCESS;
+    }
+    let args = match Args::new(&matches) {
+        Ok(args) => args,
+        Err(e) => return usage_error(&opts, e),
+    };
+
+    if args.check_config {
+        return check_config(&args.config);
+    }
+
+    logger::init(args.log_level);
+    let conf = match Config::new(&args.config) {
+        Ok(conf) => conf,
+        Err(errors) => {
+            for e in &errors.0 {
+                error!("{}", e);
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    debug!("{:?}", conf);
+
+    // Forking has to happen before the runtime starts any threads.
+    let ready = if args.foreground {
+        None
+    } else {
+        match daemon::daemonize() {
+            Ok(ready) => Some(ready),
+            Err(e) => {
+                error!("Failed to daemonize: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    if let Some(pid_file) = &args.pid_file {
+        if let Err(e) = fs::write(pid_file, format!("{}\n", process::id())) {
+            error!("Failed to write {}: {}", pid_file.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let runtime = match args.runtime {
+        Runtime::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        Runtime::MultiThread(workers) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(workers) = workers {
+                builder.worker_threads(workers);
+            }
+            builder
+        }
+    }
+    .enable_all()
+    .build();
+    let result = match runtime {
+        Ok(runtime) => runtime.block_on(run(args.config, conf, ready)),
+        Err(e) => Err(ServerError::Io(e)),
+    };
+
+    if let Some(pid_file) = &args.pid_file {
+        let _ = fs::remove_file(pid_file);
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage_error(opts: &Options, e: impl fmt::Display) -> ExitCode {
+    eprintln!("cawcaw: {}\n{}", e, opts.short_usage("cawcaw"));
+    ExitCode::FAILURE
+}
+
+/// Opens the listeners and serves clients. A daemonized server detaches from
+/// the terminal once its listeners are open, so errors opening them are seen.
+async fn run(path: PathBuf, conf: Config, ready: Option<Ready>) -> Result<(), ServerError> {
+    let mut server = Server::new(path, conf).await?;
+    if let Some(ready) = ready {
+        ready.detach().map_err(ServerError::Io)?;
+    }
+    server.run().await
+}
+
+/// Reads the config and loads its certificates without opening any
+/// listeners, for checking a config before it's used.
+fn check_config(path: &Path) -> ExitCode {
+    let result = Config::new(path)
+        .map_err(ServerError::from)
+        .and_then(|config| server::prepare_listeners(&config));
+    match result {
+        Ok(_) => {
+            println!("{}: ok", path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
         }
     }
-    server.run().await.expect("Server crash");
-    Ok(())
 }
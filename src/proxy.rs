@@ -0,0 +1,117 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Starts every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid PROXY header: {0}")]
+    Invalid(&'static str),
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`,
+/// returning the client's source and destination addresses. Returns None
+/// for connections the proxy made itself (v1 `UNKNOWN`, v2 `LOCAL`) or for
+/// protocols other than TCP, whose real addresses should be used as they
+/// are. Reads nothing past the header.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<(SocketAddr, SocketAddr)>, ProxyError> {
+    // Both versions' headers are at least this long.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(ProxyError::Invalid("missing header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, ProxyError> {
+    // One byte at a time, so none of the client's own data is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyError::Invalid("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| ProxyError::Invalid("v1 header not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, ProxyError> {
+                let ip: IpAddr = ip.parse().map_err(|_| ProxyError::Invalid("bad v1 address"))?;
+                let port: u16 = port.parse().map_err(|_| ProxyError::Invalid("bad v1 port"))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(ProxyError::Invalid("v1 address doesn't match its family"));
+                }
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some((parse(src, src_port)?, parse(dst, dst_port)?)))
+        }
+        _ => Err(ProxyError::Invalid("malformed v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<(SocketAddr, SocketAddr)>, ProxyError> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len_hi, len_lo] = header;
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Invalid("unsupported version"));
+    }
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut body).await?;
+    match version_command & 0x0f {
+        // LOCAL: a health check or similar from the proxy itself.
+        0x0 => return Ok(None),
+        0x1 => (),
+        _ => return Err(ProxyError::Invalid("unknown command")),
+    }
+    // Any TLVs after the addresses are ignored.
+    match family {
+        // TCP over IPv4.
+        0x11 => {
+            let body: &[u8; 12] = body
+                .get(..12)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(ProxyError::Invalid("v2 header too short"))?;
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some((
+                SocketAddr::new(src.into(), u16::from_be_bytes([body[8], body[9]])),
+                SocketAddr::new(dst.into(), u16::from_be_bytes([body[10], body[11]])),
+            )))
+        }
+        // TCP over IPv6.
+        0x21 => {
+            let body = body.get(..36).ok_or(ProxyError::Invalid("v2 header too short"))?;
+            let ip = |bytes: &[u8]| -> Ipv6Addr {
+                let octets: [u8; 16] = bytes.try_into().expect("slice is 16 bytes");
+                Ipv6Addr::from(octets)
+            };
+            Ok(Some((
+                SocketAddr::new(ip(&body[0..16]).into(), u16::from_be_bytes([body[32], body[33]])),
+                SocketAddr::new(ip(&body[16..32]).into(), u16::from_be_bytes([body[34], body[35]])),
+            )))
+        }
+        // UNSPEC, UDP and Unix sockets carry no usable TCP address.
+        _ => Ok(None),
+    }
+}
@@ -1,21 +1,42 @@
-use crate::client::ClientState;
-use crate::config::Config;
-use crate::{tls_socket::Socket, Client};
-use futures_util::stream::FuturesUnordered;
-use futures_util::{StreamExt, TryFutureExt};
-use proto::message::{Message, MessageContents};
+use crate::certfp::CertFp;
+use crate::class::{Classes, ConnectionInfo};
+use crate::channel::{Channel, ChannelModes, ModeChange, Rank};
+use crate::client::{self, Batch, UserMode};
+use crate::handler::{self, Responder};
+use crate::cloak::Cloak;
+use crate::config::{self, Config, ConfigErrors, Oper, WebIrc};
+use crate::dns::{self, HostResolver};
+use crate::throttle::Throttle;
+use crate::tls::{Acceptor, TlsError};
+use crate::proxy::{self, ProxyError};
+use crate::websocket;
+use crate::dnsbl::DnsblChecker;
+use crate::config::DnsblAction;
+use crate::ident::IdentClient;
+use crate::{tls_socket::{NetStream, Socket}, Client};
+use futures_util::StreamExt;
+use proto::message::{Message, MessageContents, Tag};
 use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::{collections::HashMap, net::SocketAddr};
+use std::time::Duration;
+use std::{collections::{BTreeSet, HashMap, HashSet}, net::{IpAddr, SocketAddr}};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use log::{debug, info, trace, warn};
 use thiserror::Error;
-use tokio::{net::TcpListener, net::TcpStream};
-use tokio_native_tls::native_tls;
-use tokio_native_tls::TlsAcceptor;
-use trust_dns_resolver::TokioAsyncResolver;
-use tokio::sync::RwLock;
+use tokio::net::{TcpListener, UnixListener};
+use trust_dns_resolver::error::ResolveError;
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinHandle;
+use tokio::sync::{RwLock, Semaphore};
 
-use proto::command::Command;
+use proto::caps::Capability;
+use proto::command::{CapSubCommand, Command};
 use proto::error::{ProtocolError, MessageParseError};
+use proto::prefix::Prefix;
 use proto::response::Response;
 
 macro_rules! break_err {
@@ -29,56 +50,320 @@ macro_rules! break_err {
     };
 }
 
+/// Maximum number of nicks a client may MONITOR.
+pub const MONITOR_LIMIT: usize = 100;
+
+/// Maximum length of a KICK reason.
+pub const KICKLEN: usize = 255;
+
+/// SASL mechanisms clients may log in with.
+pub const SASL_MECHANISMS: &str = "EXTERNAL";
+
+/// Maximum length of a username, including the `~` of unverified ones.
+pub const USERLEN: usize = 10;
+
+/// How long a throttled connection is given to take its ERROR.
+const THROTTLE_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connections which have finished their handshake but not yet been picked
+/// up by the server.
+const ACCEPT_QUEUE: usize = 64;
+
+/// How long a listener waits after a failed accept, so running out of file
+/// descriptors doesn't spin.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a connection from a `WEBIRC` gateway is given to send it.
+const WEBIRC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Folds a nick or channel name for comparison, matching `CASEMAPPING=ascii`.
+pub fn casefold(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// A listener's TLS acceptor, which a rehash can replace while it runs.
+pub type SharedAcceptor = Arc<ArcSwap<Acceptor>>;
+
 #[derive(Debug)]
-pub enum Listener {
-    Tls(TcpListener, TlsAcceptor),
+pub enum ListenerSocket {
+    Tls(TcpListener, SharedAcceptor),
     Plain(TcpListener),
+    /// IRC over WebSocket, optionally inside TLS.
+    WebSocket(TcpListener, Option<SharedAcceptor>, Arc<config::WebSocket>),
+    Unix(UnixListener, Arc<config::UnixSocket>),
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    name: String,
+    socket: ListenerSocket,
+    /* Permits for handshakes in progress */
+    handshakes: Arc<Semaphore>,
+    /* Proxies allowed to connect, if connections start with a PROXY header */
+    proxies: Option<Arc<Vec<IpNet>>>,
 }
 
 impl Listener {
-    pub async fn new(addr: SocketAddr) -> Result<Listener, io::Error> {
+    pub async fn new(name: String, addr: SocketAddr) -> Result<Listener, io::Error> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(Listener::Plain(listener))
+        Ok(Listener {
+            name,
+            socket: ListenerSocket::Plain(listener),
+            handshakes: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            proxies: None,
+        })
     }
 
-    pub async fn new_tls(addr: SocketAddr, tls: TlsAcceptor) -> Result<Listener, io::Error> {
+    pub async fn new_tls(
+        name: String,
+        addr: SocketAddr,
+        tls: SharedAcceptor,
+        max_handshakes: usize,
+    ) -> Result<Listener, io::Error> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(Listener::Tls(listener, tls))
+        Ok(Listener {
+            name,
+            socket: ListenerSocket::Tls(listener, tls),
+            handshakes: Arc::new(Semaphore::new(max_handshakes.max(1))),
+            proxies: None,
+        })
     }
 
-    pub async fn accept(&self) -> Result<Socket<TcpStream>, ListenerError> {
-        match self {
-            Listener::Tls(ref listener, ref acceptor) => {
-                let (socket, _) =
-                    listener
-                        .accept()
-                        .await
-                        .map_err(|e| ListenerError::ConnectionError {
-                            string: "error accepting tcp connection".to_owned(),
-                            cause: e,
-                        })?;
-                let stream =
-                    acceptor
-                        .accept(socket)
-                        .await
-                        .map_err(|e| ListenerError::TlsError {
-                            string: "error in tls connection".to_owned(),
-                            cause: e,
-                        })?;
-                Ok(Socket::Tls(stream))
+    pub async fn new_websocket(
+        name: String,
+        addr: SocketAddr,
+        tls: Option<SharedAcceptor>,
+        websocket: config::WebSocket,
+        max_handshakes: usize,
+    ) -> Result<Listener, io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Listener {
+            name,
+            socket: ListenerSocket::WebSocket(listener, tls, Arc::new(websocket)),
+            handshakes: Arc::new(Semaphore::new(max_handshakes.max(1))),
+            proxies: None,
+        })
+    }
+
+    /// Listens on a Unix domain socket, replacing a stale socket file left
+    /// behind by a previous run.
+    pub async fn new_unix(name: String, config: config::UnixSocket) -> Result<Listener, io::Error> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        match std::fs::symlink_metadata(&config.path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&config.path)?,
+            _ => {}
+        }
+        let listener = UnixListener::bind(&config.path)?;
+        std::fs::set_permissions(&config.path, std::fs::Permissions::from_mode(config.mode))?;
+        Ok(Listener {
+            name,
+            socket: ListenerSocket::Unix(listener, Arc::new(config)),
+            handshakes: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            proxies: None,
+        })
+    }
+
+    /// Makes connections start with a PROXY protocol header, and only accepts
+    /// them from the `trusted` proxies.
+    pub fn with_proxy_protocol(mut self, trusted: Vec<IpNet>) -> Listener {
+        self.proxies = Some(Arc::new(trusted));
+        self
+    }
+
+    /// Accepts connections until the server goes away, handing each one to
+    /// `clients` once its handshake completes. Handshakes run in their own
+    /// tasks so a slow client holds up nobody else.
+    pub async fn run(self, throttle: Arc<ArcSwap<Throttle>>, clients: Sender<Accepted>) {
+        loop {
+            let permit = self
+                .handshakes
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("handshake semaphore closed");
+            let throttle = throttle.load_full();
+            let incoming = match self.accept(&throttle).await {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    warn!("{}: {}", self.name, e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let tx = clients.clone();
+            tokio::spawn(async move {
+                let conn = incoming.handshake(throttle.handshake_timeout(), &throttle).await;
+                drop(permit);
+                match conn {
+                    Ok(conn) => {
+                        let _ = tx.send(conn).await;
+                    }
+                    Err(e) => info!("{}", e),
+                }
+            });
+            if clients.is_closed() {
+                return;
             }
-            Listener::Plain(listener) => {
-                let (socket, _) =
-                    listener
-                        .accept()
-                        .await
-                        .map_err(|e| ListenerError::ConnectionError {
-                            string: "error accepting tcp connection".to_owned(),
-                            cause: e,
-                        })?;
-                Ok(Socket::Plain(socket))
+        }
+    }
+
+    /// Accepts the next connection which isn't throttled. Throttled
+    /// connections are closed straight away, with an ERROR if they are plain.
+    /// The TLS handshake is left to [`Incoming::handshake`], as is throttling
+    /// of proxied connections, whose real address isn't known yet.
+    pub async fn accept(&self, throttle: &Throttle) -> Result<Incoming, ListenerError> {
+        let (listener, acceptor, websocket) = match self.socket {
+            ListenerSocket::Tls(ref listener, ref acceptor) => (listener, Some(acceptor), None),
+            ListenerSocket::Plain(ref listener) => (listener, None, None),
+            ListenerSocket::WebSocket(ref listener, ref acceptor, ref websocket) => {
+                (listener, acceptor.as_ref(), Some(websocket))
+            }
+            ListenerSocket::Unix(ref listener, ref config) => return self.accept_unix(listener, config).await,
+        };
+        loop {
+            let (socket, addr) =
+                listener
+                    .accept()
+                    .await
+                    .map_err(|e| ListenerError::ConnectionError {
+                        string: "error accepting tcp connection".to_owned(),
+                        cause: e,
+                    })?;
+            if let Some(proxies) = &self.proxies {
+                let ip = addr.ip().to_canonical();
+                if !proxies.iter().any(|net| net.contains(&ip)) {
+                    warn!("{}: connection from untrusted proxy {}", self.name, ip);
+                    continue;
+                }
+            }
+            if self.proxies.is_some() || throttle.allow(addr.ip()) {
+                let local = socket.local_addr().map_err(|e| ListenerError::ConnectionError {
+                    string: "connection has no address".to_owned(),
+                    cause: e,
+                })?;
+                return Ok(Incoming {
+                    socket: NetStream::Tcp(socket),
+                    peer: addr,
+                    local,
+                    hostname: None,
+                    unix: false,
+                    // Loaded now, so a rehash while waiting applies to this connection.
+                    tls: acceptor.map(|a| Acceptor::clone(&a.load())),
+                    websocket: websocket.cloned(),
+                    proxied: self.proxies.is_some(),
+                    listener: self.name.clone(),
+                });
+            }
+            if acceptor.is_none() && websocket.is_none() {
+                // Best effort; a client which isn't reading just misses it.
+                tokio::spawn(async move {
+                    let mut socket = socket;
+                    let error = b"ERROR :Closing link: Too many connections, try again later\r\n";
+                    let _ = tokio::time::timeout(THROTTLE_WRITE_TIMEOUT, socket.write_all(error)).await;
+                });
+            }
+        }
+    }
+
+    /// Unix socket clients are local, so they are never throttled, and get
+    /// the listener's address and hostname.
+    async fn accept_unix(&self, listener: &UnixListener, config: &config::UnixSocket) -> Result<Incoming, ListenerError> {
+        let (socket, _) = listener.accept().await.map_err(|e| ListenerError::ConnectionError {
+            string: "error accepting unix connection".to_owned(),
+            cause: e,
+        })?;
+        let addr = SocketAddr::new(config.address, 0);
+        Ok(Incoming {
+            socket: NetStream::Unix(socket),
+            peer: addr,
+            local: addr,
+            hostname: Some(config.hostname.clone()),
+            unix: true,
+            tls: None,
+            websocket: None,
+            proxied: false,
+            listener: self.name.clone(),
+        })
+    }
+}
+
+/// A client connection which has completed its handshakes.
+#[derive(Debug)]
+pub struct Accepted {
+    pub socket: Socket<NetStream>,
+    /// The name of the listener it connected to.
+    pub listener: String,
+    /// The client's address, which comes from the PROXY header if there was one.
+    pub peer: SocketAddr,
+    /// The address the client connected to.
+    pub local: SocketAddr,
+    /// A hostname the listener gives its clients, in place of looking one up.
+    pub hostname: Option<String>,
+    /// Whether it came over a Unix domain socket, with no address of its own.
+    pub unix: bool,
+}
+
+/// A connection accepted by a listener, before any PROXY, TLS or WebSocket
+/// handshake.
+#[derive(Debug)]
+pub struct Incoming {
+    socket: NetStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    hostname: Option<String>,
+    unix: bool,
+    tls: Option<Acceptor>,
+    websocket: Option<Arc<config::WebSocket>>,
+    /* Whether the connection starts with a PROXY header */
+    proxied: bool,
+    listener: String,
+}
+
+impl Incoming {
+    /// Reads the PROXY header and completes the TLS and WebSocket handshakes
+    /// the listener needs, giving up after `timeout`. Proxied connections are
+    /// throttled by the address in their header.
+    pub async fn handshake(self, timeout: Duration, throttle: &Throttle) -> Result<Accepted, ListenerError> {
+        tokio::time::timeout(timeout, self.upgrade(throttle))
+            .await
+            .map_err(|_| ListenerError::HandshakeTimeout)?
+    }
+
+    async fn upgrade(self, throttle: &Throttle) -> Result<Accepted, ListenerError> {
+        let Incoming { mut socket, mut peer, mut local, hostname, unix, tls, websocket, proxied, listener } = self;
+        if proxied {
+            if let Some((source, destination)) = proxy::read_header(&mut socket).await? {
+                peer = source;
+                local = destination;
+            }
+            if !throttle.allow(peer.ip()) {
+                return Err(ListenerError::Throttled(peer.ip()));
             }
         }
+        let socket = match tls {
+            Some(acceptor) => acceptor
+                .accept(socket)
+                .await
+                .map_err(|e| ListenerError::TlsError {
+                    string: "error in tls connection".to_owned(),
+                    cause: e,
+                })?,
+            None => Socket::Plain(socket),
+        };
+        let socket = match websocket {
+            Some(config) => {
+                let stream = websocket::accept(socket, &config)
+                    .await
+                    .map_err(|e| ListenerError::WebSocketError {
+                        string: "error in websocket handshake".to_owned(),
+                        cause: e,
+                    })?;
+                Socket::Ws(Box::new(stream))
+            }
+            None => socket,
+        };
+        Ok(Accepted { socket, listener, peer, local, hostname, unix })
     }
 }
 
@@ -94,28 +379,172 @@ pub enum ListenerError {
     TlsError {
         string: String,
         #[source]
-        cause: native_tls::Error,
+        cause: TlsError,
+    },
+    #[error("websocket error: {}", string)]
+    WebSocketError {
+        string: String,
+        #[source]
+        cause: tokio_tungstenite::tungstenite::Error,
     },
+    #[error("proxy error: {0}")]
+    ProxyError(#[from] ProxyError),
+    #[error("proxied connection from {0} throttled")]
+    Throttled(IpAddr),
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+}
+
+/// Sets up the TLS of the listeners in `config`, so a config whose
+/// certificates don't load is refused before anything is opened. Returns
+/// each TLS listener's acceptor by name.
+pub fn prepare_listeners(config: &Config) -> Result<HashMap<String, Acceptor>, ServerError> {
+    let mut acceptors = HashMap::new();
+    for listener in &config.server.listeners {
+        if let Some(tls) = &listener.tls {
+            let acceptor = Acceptor::new(tls).map_err(|cause| ServerError::Tls {
+                listener: listener.name.clone(),
+                cause,
+            })?;
+            acceptors.insert(listener.name.clone(), acceptor);
+        }
+    }
+    Ok(acceptors)
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
-enum ServerPhase {
-    Startup,
-    Running,
+/// A listener's accept task, and the config it was opened with.
+#[derive(Debug)]
+struct RunningListener {
+    config: config::Listener,
+    acceptor: Option<SharedAcceptor>,
+    task: JoinHandle<()>,
+    /* Reloads the acceptor's certificates when their files change */
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl RunningListener {
+    /// Whether the listener can be kept for `config`, only needing its TLS
+    /// acceptor replaced.
+    fn can_become(&self, config: &config::Listener) -> bool {
+        self.config.address == config.address
+            && self.config.unix == config.unix
+            && self.config.websocket == config.websocket
+            && self.config.proxy_protocol == config.proxy_protocol
+            && self.config.max_handshakes == config.max_handshakes
+            && self.config.tls.is_some() == config.tls.is_some()
+    }
+
+    /// Hands new connections to `acceptor`, which has the reloaded
+    /// certificates. Connections already made keep their old ones.
+    fn replace_acceptor(&mut self, config: &config::Listener, acceptor: Option<Acceptor>) {
+        if let (Some(shared), Some(acceptor), Some(tls)) = (&self.acceptor, acceptor, config.tls.clone()) {
+            if let Some(watcher) = self.watcher.take() {
+                watcher.abort();
+            }
+            shared.store(Arc::new(acceptor.clone()));
+            self.watcher = Some(tokio::spawn(acceptor.watch(tls)));
+        }
+        self.config = config.clone();
+    }
+
+    /// Stops accepting, waiting for the socket to close so its address can
+    /// be bound again.
+    async fn stop(self) {
+        if let Some(watcher) = self.watcher {
+            watcher.abort();
+        }
+        self.task.abort();
+        let _ = self.task.await;
+    }
+}
+
+/// An operator's `REHASH`, to be answered once the config has been reloaded.
+#[derive(Debug)]
+pub struct RehashRequest {
+    nick: String,
+    sender: client::Sender,
+}
+
+/// What the server's main loop wakes up for.
+enum Event {
+    Accepted(Option<Accepted>),
+    /// From an operator, or SIGHUP if None.
+    Rehash(Option<RehashRequest>),
+    /// SIGTERM or SIGINT.
+    Shutdown,
+}
+
+/// The Strict Transport Security policy advertised in the `sts` capability.
+#[derive(Debug, Clone)]
+struct StsPolicy {
+    port: u16,
+    duration: u64,
+    preload: bool,
+}
+
+impl StsPolicy {
+    /// None if STS is disabled, or there's no TLS port to send clients to.
+    fn new(config: &Config) -> Option<StsPolicy> {
+        if !config.sts.enabled {
+            return None;
+        }
+        let tls_port = config
+            .server
+            .listeners
+            .iter()
+            .find(|l| l.tls.is_some() && l.websocket.is_none())
+            .and_then(|l| l.address)
+            .map(|address| address.port());
+        Some(StsPolicy {
+            port: config.sts.port.or(tls_port)?,
+            duration: config.sts.duration,
+            preload: config.sts.preload,
+        })
+    }
+
+    /// Plaintext clients are told where to reconnect; secure clients how
+    /// long to keep to TLS.
+    fn value(&self, secure: bool) -> String {
+        if !secure {
+            return format!("port={}", self.port);
+        }
+        let mut value = format!("duration={}", self.duration);
+        if self.preload {
+            value.push_str(",preload");
+        }
+        value
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerState {
     hostname: String,
+    config: Arc<ArcSwap<Config>>,
+    /* Where the config is read from, for RPL_REHASHING */
+    config_file: String,
+    cloak: Option<Cloak>,
+    sts: Option<StsPolicy>,
+    rehash: Sender<RehashRequest>,
     clients: Arc<RwLock<HashMap<String, Arc<RwLock<Client>>>>>,
+    channels: Arc<RwLock<HashMap<String, Channel>>>,
+    /* Casefolded nick to the casefolded nicks monitoring it */
+    monitors: Arc<RwLock<HashMap<String, HashSet<String>>>>,
 }
 
 impl ServerState {
 
-    pub fn new(hostname: String) -> Self {
+    pub fn new(config: Arc<ArcSwap<Config>>, config_file: String, rehash: Sender<RehashRequest>) -> Self {
+        let current = config.load();
         Self {
-            hostname,
+            hostname: current.server.name.clone(),
+            cloak: Cloak::new(&current.cloak),
+            sts: StsPolicy::new(&current),
+            config: config.clone(),
+            config_file,
+            rehash,
             clients: Arc::new(RwLock::new(HashMap::new())),
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            monitors: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -123,162 +552,920 @@ impl ServerState {
         &self.hostname
     }
 
+    /// The cloak generator, if cloaking is configured.
+    pub fn cloak(&self) -> Option<&Cloak> {
+        self.cloak.as_ref()
+    }
+
+    /// Takes on the parts of a reloaded config the state keeps its own copy
+    /// of. The server's name stays as it is, since clients already know it.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.cloak = Cloak::new(&config.cloak);
+        self.sts = StsPolicy::new(config);
+    }
+
+    pub fn config_file(&self) -> &str {
+        &self.config_file
+    }
+
+    /// Asks the server to reload its config, and to tell `nick` how it went.
+    /// A rehash which is already waiting covers this one.
+    pub fn request_rehash(&self, nick: String, sender: client::Sender) {
+        let _ = self.rehash.try_send(RehashRequest { nick, sender });
+    }
+
+    pub fn find_oper(&self, name: &str) -> Option<Oper> {
+        self.config.load().oper.iter().find(|o| o.name == name).cloned()
+    }
+
+    /// The account a client with the certificate `certfp` logs in to.
+    pub fn find_account(&self, certfp: &CertFp) -> Option<String> {
+        self.config
+            .load()
+            .account
+            .iter()
+            .find(|a| a.certfp.iter().any(|fp| certfp.matches(fp)))
+            .map(|a| a.name.clone())
+    }
+
+    /// The client-only tags of `message` which may be relayed to other clients.
+    pub fn relayed_tags(&self, message: &Message) -> Vec<Tag> {
+        message
+            .tags
+            .iter()
+            .flatten()
+            .filter(|t| t.is_client_only() && self.config.load().client_tags.permits(&t.0))
+            .cloned()
+            .collect()
+    }
+
+    /// Tokens for RPL_ISUPPORT, sent after the welcome.
+    pub fn isupport(&self) -> Vec<String> {
+        vec![
+            Rank::isupport(),
+            "CHANTYPES=#".to_owned(),
+            "CASEMAPPING=ascii".to_owned(),
+            ChannelModes::ISUPPORT.to_owned(),
+            ChannelModes::EXTBAN.to_owned(),
+            format!("KICKLEN={}", KICKLEN),
+            format!("USERLEN={}", USERLEN),
+            format!("MONITOR={}", MONITOR_LIMIT),
+            "BOT=B".to_owned(),
+        ]
+    }
+
     pub async fn send<M: Into<Message>>(&self, client: &Client, msg: M) -> Result<(), ProtocolError> {
         let mut msg: Message = msg.into();
-        msg.set_prefix(&self.get_name());
+        msg.set_prefix(self.get_name());
         client.sender().send(msg)
     }
 
+    /// Opens a batch to `client`, which degrades to plain messages if the
+    /// client has not negotiated `batch`. Nothing opens one yet: a single
+    /// server has no netsplits, and there is no chathistory.
+    #[allow(dead_code)]
+    pub fn batch<S: Into<String>>(
+        &self,
+        client: &Client,
+        kind: S,
+        params: Vec<String>,
+    ) -> Result<Batch, ProtocolError> {
+        let prefix = Some(self.get_name().into());
+        if client.state().has_cap(Capability::Batch) {
+            client.sender().batch(prefix, kind, params)
+        } else {
+            Ok(client.sender().unbatched(prefix))
+        }
+    }
+
+    /// The STS policy for `client`. Unix socket clients have no TLS port to
+    /// move to, and WebSocket clients can't speak IRC over raw TLS.
+    fn sts_for(&self, client: &Client) -> Option<&StsPolicy> {
+        let config = self.config.load();
+        let listener = config.server.listeners.iter().find(|l| l.name == client.listener())?;
+        if listener.unix.is_some() || listener.websocket.is_some() {
+            return None;
+        }
+        self.sts.as_ref()
+    }
+
+    pub fn handle_cap(
+        &self,
+        client: &mut Client,
+        responder: &mut Responder,
+        sub: &CapSubCommand,
+        arg: Option<&str>,
+    ) {
+        let target = match client.state().nick() {
+            "" => "*".to_owned(),
+            nick => nick.to_owned(),
+        };
+        match sub {
+            CapSubCommand::LS => {
+                // Capability values need CAP LS 302, and sts is nothing without one.
+                let version = arg.and_then(|v| v.parse::<u32>().ok()).unwrap_or(0);
+                let mut caps: Vec<String> = Capability::ALL
+                    .iter()
+                    .map(|c| match c {
+                        Capability::Sasl if version >= 302 => format!("{}={}", c.name(), SASL_MECHANISMS),
+                        c => c.name().to_owned(),
+                    })
+                    .collect();
+                if let Some(sts) = self.sts_for(client).filter(|_| version >= 302) {
+                    caps.push(format!("sts={}", sts.value(client.state().has_mode(UserMode::Secure))));
+                }
+                responder.send(Command::Cap(Some(target), CapSubCommand::LS, Some(caps.join(" ")), None));
+            }
+            CapSubCommand::LIST => {
+                let caps: Vec<&str> = client.state().caps().map(|c| c.name()).collect();
+                responder.send(Command::Cap(Some(target), CapSubCommand::LIST, Some(caps.join(" ")), None));
+            }
+            CapSubCommand::REQ => {
+                let requested = arg.unwrap_or("");
+                let caps: Option<Vec<(Capability, bool)>> = requested
+                    .split_whitespace()
+                    .map(|c| match c.strip_prefix('-') {
+                        Some(c) => c.parse::<Capability>().ok().map(|c| (c, false)),
+                        None => c.parse::<Capability>().ok().map(|c| (c, true)),
+                    })
+                    .collect();
+                match caps {
+                    Some(caps) => {
+                        for (cap, enabled) in caps {
+                            client.set_cap(cap, enabled);
+                        }
+                        responder.send(Command::Cap(Some(target), CapSubCommand::ACK, Some(requested.to_owned()), None));
+                    }
+                    None => responder.send(Command::Cap(Some(target), CapSubCommand::NAK, Some(requested.to_owned()), None)),
+                }
+            }
+            CapSubCommand::END => (),
+            sub => responder.send(Response::ErrInvalidCapCmd(sub.to_str().to_owned())),
+        }
+    }
+
     pub async fn check_nick(&self, nick: &str) -> bool {
-        self.clients.read().await.contains_key(nick)
+        self.clients.read().await.contains_key(&casefold(nick))
     }
 
-    pub async fn register_client(&mut self, mut client: Client, password: Option<String>, nick: &String, user: &String, real: &String) -> Result<Arc<RwLock<Client>>, Response> {
+    pub async fn register_client(&mut self, mut client: Client, nick: &String, user: &String, real: &String) -> Result<Arc<RwLock<Client>>, Response> {
         let mut clients = self.clients.write().await;
-        client.register(&nick, &user, &real);
+        client.register(nick, user, real);
+        let prefix = client.prefix();
         let client = Arc::new(RwLock::new(client));
-        clients.insert(nick.to_string(), client.clone());
+        clients.insert(casefold(nick), client.clone());
+        drop(clients);
+        self.notify_monitors(nick, Some(prefix)).await;
         Ok(client)
     }
+
+    pub async fn get_client(&self, nick: &str) -> Option<Arc<RwLock<Client>>> {
+        self.clients.read().await.get(&casefold(nick)).cloned()
+    }
+
+    /// Moves a client from `old` to `new` in the client map, its channels and
+    /// the monitor index. The caller is responsible for updating the client.
+    pub async fn change_nick(&self, old: &str, new: &str, monitoring: &HashSet<String>) -> Result<(), Response> {
+        let mut clients = self.clients.write().await;
+        if casefold(old) != casefold(new) && clients.contains_key(&casefold(new)) {
+            return Err(Response::ErrNickCollision(new.to_owned()));
+        }
+        let client = match clients.remove(&casefold(old)) {
+            Some(client) => client,
+            None => return Err(Response::ErrNoSuchNick(old.to_owned(), old.to_owned())),
+        };
+        clients.insert(casefold(new), client);
+        drop(clients);
+        for channel in self.channels.write().await.values_mut() {
+            channel.rename_member(old, new);
+        }
+        let mut monitors = self.monitors.write().await;
+        for target in monitoring {
+            if let Some(watchers) = monitors.get_mut(target) {
+                watchers.remove(&casefold(old));
+                watchers.insert(casefold(new));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn add_monitor(&self, watcher: &str, target: &str) {
+        self.monitors
+            .write()
+            .await
+            .entry(casefold(target))
+            .or_insert_with(HashSet::new)
+            .insert(casefold(watcher));
+    }
+
+    pub async fn remove_monitor(&self, watcher: &str, target: &str) {
+        let mut monitors = self.monitors.write().await;
+        if let Some(watchers) = monitors.get_mut(&casefold(target)) {
+            watchers.remove(&casefold(watcher));
+            if watchers.is_empty() {
+                monitors.remove(&casefold(target));
+            }
+        }
+    }
+
+    /// Tells clients monitoring `nick` that it came online (with its prefix)
+    /// or went offline.
+    pub async fn notify_monitors(&self, nick: &str, online: Option<Prefix>) {
+        let watchers: Vec<String> = match self.monitors.read().await.get(&casefold(nick)) {
+            Some(watchers) => watchers.iter().cloned().collect(),
+            None => return,
+        };
+        for watcher in watchers {
+            if let Some(client) = self.get_client(&watcher).await {
+                let client = client.read().await;
+                let target = client.state().nick().to_owned();
+                let response = match online {
+                    Some(ref prefix) => Response::RplMonOnline(target, prefix.to_string()),
+                    None => Response::RplMonOffline(target, nick.to_owned()),
+                };
+                // A failed send means the watcher is on its way out.
+                let _ = self.send(&client, response).await;
+            }
+        }
+    }
+
+    /// Removes a client from the server and its channels, sending `QUIT` to
+    /// everyone it shared a channel with.
+    pub async fn remove_client(&mut self, nick: &str, reason: Option<String>) {
+        let client = match self.clients.write().await.remove(&casefold(nick)) {
+            Some(client) => client,
+            None => return,
+        };
+        let monitoring = client.read().await.state().monitoring().clone();
+        for target in monitoring.iter() {
+            self.remove_monitor(nick, target).await;
+        }
+        self.notify_monitors(nick, None).await;
+        let others = self.common_nicks(nick).await;
+        let mut channels = self.channels.write().await;
+        for channel in channels.values_mut() {
+            channel.part(nick);
+        }
+        channels.retain(|_, c| !c.is_empty());
+        drop(channels);
+        let mut msg: Message = Command::QUIT(reason).into();
+        msg.prefix = Some(client.read().await.prefix());
+        self.send_to_nicks(others.iter(), None, msg).await;
+    }
+
+    /// Adds `nick` to a channel, creating it if needed. Returns the channel's
+    /// name and its members, or `None` if `nick` was already a member.
+    pub async fn join_channel(&self, name: &str, client: &Client, key: Option<&str>) -> Result<Option<(String, Vec<String>)>, Response> {
+        let state = client.state();
+        let nick = state.nick();
+        let mut channels = self.channels.write().await;
+        let channel = channels
+            .entry(casefold(name))
+            .or_insert_with(|| Channel::new(name));
+        if channel.is_member(nick) {
+            return Ok(None);
+        }
+        if let Some(refusal) = channel.refusal(client, key) {
+            return Err(refusal);
+        }
+        channel.join(nick);
+        Ok(Some((channel.name().to_owned(), channel.members().cloned().collect())))
+    }
+
+    /// Records an invite of `target` to a channel by `nick`. Returns the
+    /// channel's name and the operators to notify of the invite.
+    pub async fn invite(&self, name: &str, nick: &str, target: &str) -> Result<(String, Vec<String>), Response> {
+        let mut channels = self.channels.write().await;
+        let channel = channels
+            .get_mut(&casefold(name))
+            .ok_or_else(|| Response::ErrNoSuchChannel(nick.to_owned(), name.to_owned()))?;
+        if !channel.is_member(nick) {
+            return Err(Response::ErrNotOnChannel(nick.to_owned(), channel.name().to_owned()));
+        }
+        if channel.member_name(target).is_some() {
+            return Err(Response::ErrUserOnChannel(nick.to_owned(), target.to_owned(), channel.name().to_owned()));
+        }
+        if channel.modes().invite_only && !channel.has_rank(nick, Rank::Operator) {
+            return Err(Response::ErrChanOPrivsNeeded(nick.to_owned(), channel.name().to_owned()));
+        }
+        channel.invite(target);
+        let operators = channel
+            .members()
+            .filter(|m| *m != nick && channel.has_rank(m, Rank::Operator))
+            .cloned()
+            .collect();
+        Ok((channel.name().to_owned(), operators))
+    }
+
+    /// Removes `target` from a channel on behalf of `nick`. Returns the
+    /// channel's name, the target's nick and the members before the kick.
+    pub async fn kick(&self, name: &str, nick: &str, target: &str) -> Result<(String, String, Vec<String>), Response> {
+        let mut channels = self.channels.write().await;
+        let channel = channels
+            .get_mut(&casefold(name))
+            .ok_or_else(|| Response::ErrNoSuchChannel(nick.to_owned(), name.to_owned()))?;
+        if !channel.is_member(nick) {
+            return Err(Response::ErrNotOnChannel(nick.to_owned(), channel.name().to_owned()));
+        }
+        if !channel.has_rank(nick, Rank::Operator) {
+            return Err(Response::ErrChanOPrivsNeeded(nick.to_owned(), channel.name().to_owned()));
+        }
+        let target = channel.member_name(target).cloned().ok_or_else(|| {
+            Response::ErrUserNotInChannel(nick.to_owned(), target.to_owned(), channel.name().to_owned())
+        })?;
+        // Only members of a lower rank may be kicked.
+        if !channel.outranks(nick, &target) {
+            return Err(Response::ErrChanOPrivsNeeded(nick.to_owned(), channel.name().to_owned()));
+        }
+        let members = channel.members().cloned().collect();
+        channel.part(&target);
+        let name = channel.name().to_owned();
+        if channel.is_empty() {
+            channels.remove(&casefold(&name));
+        }
+        Ok((name, target, members))
+    }
+
+    /// Applies a mode string to a channel on behalf of `nick`. Returns the
+    /// channel's name, its members and the modes applied.
+    pub async fn change_channel_modes(&self, name: &str, nick: &str, modes: &str, params: &[String]) -> Result<(String, Vec<String>, ModeChange), Response> {
+        let mut channels = self.channels.write().await;
+        let channel = channels
+            .get_mut(&casefold(name))
+            .ok_or_else(|| Response::ErrNoSuchChannel(nick.to_owned(), name.to_owned()))?;
+        if !channel.has_rank(nick, Rank::Operator) {
+            return Err(Response::ErrChanOPrivsNeeded(nick.to_owned(), channel.name().to_owned()));
+        }
+        let change = channel.apply_modes(nick, modes, params);
+        Ok((channel.name().to_owned(), channel.members().cloned().collect(), change))
+    }
+
+    /// Removes `nick` from a channel. Returns the channel's name and its
+    /// members before `nick` left.
+    pub async fn part_channel(&self, name: &str, nick: &str) -> Result<(String, Vec<String>), Response> {
+        let mut channels = self.channels.write().await;
+        let channel = channels
+            .get_mut(&casefold(name))
+            .ok_or_else(|| Response::ErrNoSuchChannel(nick.to_owned(), name.to_owned()))?;
+        let members: Vec<String> = channel.members().cloned().collect();
+        if !channel.part(nick) {
+            return Err(Response::ErrNotOnChannel(nick.to_owned(), name.to_owned()));
+        }
+        let name = channel.name().to_owned();
+        if channel.is_empty() {
+            channels.remove(&casefold(&name));
+        }
+        Ok((name, members))
+    }
+
+    pub async fn get_channel(&self, name: &str) -> Option<Channel> {
+        self.channels.read().await.get(&casefold(name)).cloned()
+    }
+
+    pub async fn channel_members(&self, name: &str) -> Option<(String, Vec<String>)> {
+        let channels = self.channels.read().await;
+        let channel = channels.get(&casefold(name))?;
+        Some((channel.name().to_owned(), channel.members().cloned().collect()))
+    }
+
+    /// The channels `nick` is in, with its ranks in each.
+    pub async fn channels_of(&self, nick: &str) -> Vec<(String, BTreeSet<Rank>)> {
+        self.channels
+            .read()
+            .await
+            .values()
+            .filter_map(|c| c.ranks(nick).map(|r| (c.name().to_owned(), r.clone())))
+            .collect()
+    }
+
+    /// Nicks sharing at least one channel with `nick`, excluding `nick` itself.
+    pub async fn common_nicks(&self, nick: &str) -> HashSet<String> {
+        self.channels
+            .read()
+            .await
+            .values()
+            .filter(|c| c.is_member(nick))
+            .flat_map(|c| c.members().cloned())
+            .filter(|n| n != nick)
+            .collect()
+    }
+
+    /// Sends a server notice to operators with `+s`.
+    pub async fn notice_opers(&self, text: &str) {
+        for client in self.clients.read().await.values() {
+            let client = client.read().await;
+            if client.state().has_mode(UserMode::Oper) && client.state().has_mode(UserMode::ServerNotices) {
+                let mut msg: Message = Command::Notice(client.state().nick(), &format!("*** {}", text)).into();
+                msg.prefix = Some(self.hostname.as_str().into());
+                let _ = client.sender().send(msg);
+            }
+        }
+    }
+
+    /// Sends `msg` to each of `nicks`, skipping clients without `cap` if given.
+    /// The caller must not hold the write lock of any of the recipients.
+    pub async fn send_to_nicks<'a, I: Iterator<Item = &'a String>>(
+        &self,
+        nicks: I,
+        cap: Option<Capability>,
+        msg: Message,
+    ) {
+        let clients = self.clients.read().await;
+        for nick in nicks {
+            if let Some(client) = clients.get(&casefold(nick)) {
+                let client = client.read().await;
+                if cap.is_none_or(|cap| client.state().has_cap(cap)) {
+                    // A failed send means the client is on its way out.
+                    let _ = client.sender().send(msg.clone());
+                }
+            }
+        }
+    }
+
+    /// Sends `with_cap` to each of `nicks` which negotiated `cap`, and
+    /// `without_cap`, if any, to the rest.
+    pub async fn send_to_nicks_by_cap<'a, I: Iterator<Item = &'a String>>(
+        &self,
+        nicks: I,
+        cap: Capability,
+        with_cap: Message,
+        without_cap: Option<Message>,
+    ) {
+        let clients = self.clients.read().await;
+        for nick in nicks {
+            if let Some(client) = clients.get(&casefold(nick)) {
+                let client = client.read().await;
+                let msg = if client.state().has_cap(cap) {
+                    &with_cap
+                } else {
+                    match without_cap {
+                        Some(ref msg) => msg,
+                        None => continue,
+                    }
+                };
+                // A failed send means the client is on its way out.
+                let _ = client.sender().send(msg.clone());
+            }
+        }
+    }
+
+    /// Sends `msg` to the clients sharing a channel with `nick` which have `cap`.
+    pub async fn notify_common(&self, nick: &str, cap: Capability, msg: Message) {
+        let others = self.common_nicks(nick).await;
+        self.send_to_nicks(others.iter(), Some(cap), msg).await;
+    }
 }
 
 #[derive(Debug)]
 pub struct Server {
     state: Arc<RwLock<ServerState>>,
-    resolver: TokioAsyncResolver,
-    listeners: Vec<Listener>,
-    phase: ServerPhase,
+    /* Shared with the server state, and replaced on rehash */
+    config: Arc<ArcSwap<Config>>,
+    config_path: PathBuf,
+    resolver: HostResolver,
+    ident: IdentClient,
+    dnsbl: DnsblChecker,
+    classes: Classes,
+    throttle: Arc<ArcSwap<Throttle>>,
+    webirc: Arc<Vec<WebIrc>>,
+    /* Listener name to its accept task */
+    listeners: HashMap<String, RunningListener>,
+    /* Connections which have finished their handshakes, from every listener */
+    accepted_tx: Sender<Accepted>,
+    accepted: mpsc::Receiver<Accepted>,
+    rehash_requests: mpsc::Receiver<RehashRequest>,
 }
 
 impl Server {
-    pub async fn new(hostname: String) -> Result<Server, ServerError> {
-        let resolver =
-            TokioAsyncResolver::tokio_from_system_conf().expect("Failed to create DNS resolver");
-        Ok(Self {
+    /// Sets up the server and opens its listeners, which start accepting
+    /// straight away. `config_path` is where the config is read again from on
+    /// rehash.
+    pub async fn new(config_path: PathBuf, config: Config) -> Result<Server, ServerError> {
+        let mut acceptors = prepare_listeners(&config)?;
+        let resolver = HostResolver::new(&config.dns).map_err(ServerError::Resolver)?;
+        let (rehash_tx, rehash_requests) = mpsc::channel(1);
+        let (accepted_tx, accepted) = mpsc::channel(ACCEPT_QUEUE);
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let current = config.load_full();
+        let state = ServerState::new(config.clone(), config_path.display().to_string(), rehash_tx);
+        let mut server = Self {
+            dnsbl: DnsblChecker::new(current.dnsbl.clone(), &resolver),
+            classes: Classes::new(&current.class, &current.allow),
+            throttle: Arc::new(ArcSwap::from_pointee(Throttle::new(&current.throttle))),
+            webirc: Arc::new(current.webirc.clone()),
             resolver,
-            listeners: Vec::new(),
-            state: Arc::new(RwLock::new(ServerState::new(hostname))),
-            phase: ServerPhase::Startup,
-        })
+            ident: IdentClient::new(&current.ident),
+            listeners: HashMap::new(),
+            state: Arc::new(RwLock::new(state)),
+            config,
+            config_path,
+            accepted_tx,
+            accepted,
+            rehash_requests,
+        };
+        for listener in &current.server.listeners {
+            server.start_listener(listener, acceptors.remove(&listener.name)).await?;
+        }
+        Ok(server)
     }
 
-    pub async fn add_tls_listener(
-        &mut self,
-        addr: SocketAddr,
-        tls: TlsAcceptor,
-    ) -> Result<(), ServerError> {
-        if self.phase != ServerPhase::Startup {
-            return Err(ServerError::ListenerModification(
-                "attempt to add listener whilst running".to_owned(),
-            ));
-        }
-        let listener = Listener::new_tls(addr, tls)
-            .map_err(|e| ServerError::Io(e))
-            .await?;
-        self.listeners.push(listener);
+    /// Opens the listener `config` describes and starts accepting on it.
+    async fn start_listener(&mut self, config: &config::Listener, acceptor: Option<Acceptor>) -> Result<(), ServerError> {
+        let shared = acceptor.as_ref().map(|a| Arc::new(ArcSwap::from_pointee(a.clone())));
+        let name = config.name.clone();
+        let listener = if let Some(unix) = &config.unix {
+            Listener::new_unix(name.clone(), unix.clone()).await
+        } else {
+            let addr = config.address.expect("listener addresses are checked by Config::new");
+            match (&config.websocket, &shared) {
+                (Some(websocket), tls) => {
+                    Listener::new_websocket(name.clone(), addr, tls.clone(), websocket.clone(), config.max_handshakes).await
+                }
+                (None, Some(tls)) => Listener::new_tls(name.clone(), addr, tls.clone(), config.max_handshakes).await,
+                (None, None) => Listener::new(name.clone(), addr).await,
+            }
+        };
+        let listener = listener.map_err(|cause| ServerError::Bind {
+            listener: name.clone(),
+            cause,
+        })?;
+        let listener = if config.proxy_protocol {
+            listener.with_proxy_protocol(self.config.load().proxy.trusted.clone())
+        } else {
+            listener
+        };
+        let watcher = acceptor
+            .zip(config.tls.clone())
+            .map(|(acceptor, tls)| tokio::spawn(acceptor.watch(tls)));
+        let task = tokio::spawn(listener.run(self.throttle.clone(), self.accepted_tx.clone()));
+        self.listeners.insert(name, RunningListener {
+            config: config.clone(),
+            acceptor: shared,
+            task,
+            watcher,
+        });
         Ok(())
     }
 
-    pub async fn add_listener(&mut self, addr: SocketAddr) -> Result<(), ServerError> {
-        if self.phase != ServerPhase::Startup {
-            return Err(ServerError::ListenerModification(
-                "attempt to add listener whilst running".to_owned(),
-            ));
+    /// Reads the config file again and puts it in place. Nothing changes
+    /// unless the whole file is valid and every certificate loads. Listeners
+    /// are then opened and closed to match; those which fail to open are
+    /// returned, with the rest of the config in use regardless.
+    pub async fn rehash(&mut self) -> Result<Vec<ServerError>, ServerError> {
+        let config = Config::new(&self.config_path)?;
+        let mut acceptors = prepare_listeners(&config)?;
+        let resolver = HostResolver::new(&config.dns).map_err(ServerError::Resolver)?;
+
+        let old = self.config.swap(Arc::new(config));
+        let config = self.config.load_full();
+        self.dnsbl = DnsblChecker::new(config.dnsbl.clone(), &resolver);
+        self.resolver = resolver;
+        self.ident = IdentClient::new(&config.ident);
+        self.classes = self.classes.reconfigured(&config.class, &config.allow);
+        self.throttle.store(Arc::new(self.throttle.load().reconfigured(&config.throttle)));
+        self.webirc = Arc::new(config.webirc.clone());
+        self.state.write().await.reconfigure(&config);
+        if old.cloak != config.cloak {
+            self.recloak().await;
         }
-        let listener = Listener::new(addr).map_err(|e| ServerError::Io(e)).await?;
-        self.listeners.push(listener);
-        Ok(())
+
+        // Listeners which can't be kept are closed before any are opened, as
+        // a replacement may want the same address.
+        let proxies_changed = old.proxy.trusted != config.proxy.trusted;
+        let names: Vec<String> = self.listeners.keys().cloned().collect();
+        for name in names {
+            let keep = config.server.listeners.iter().find(|l| l.name == name).is_some_and(|l| {
+                self.listeners[&name].can_become(l) && !(l.proxy_protocol && proxies_changed)
+            });
+            if !keep {
+                if let Some(running) = self.listeners.remove(&name) {
+                    running.stop().await;
+                }
+            }
+        }
+        let mut failed = Vec::new();
+        for listener in &config.server.listeners {
+            let acceptor = acceptors.remove(&listener.name);
+            match self.listeners.get_mut(&listener.name) {
+                Some(running) => running.replace_acceptor(listener, acceptor),
+                None => {
+                    if let Err(e) = self.start_listener(listener, acceptor).await {
+                        failed.push(e);
+                    }
+                }
+            }
+        }
+        Ok(failed)
     }
 
-    pub fn set_phase(&mut self, phase: ServerPhase) {
-        self.phase = phase;
+    /// Gives every client a cloak under the current keys, or takes cloaks
+    /// away if cloaking was turned off, announcing the new hosts.
+    async fn recloak(&self) {
+        let state = self.state.read().await;
+        let clients: Vec<_> = state.clients.read().await.values().cloned().collect();
+        for client in clients {
+            let mut client = client.write().await;
+            let old = client.prefix();
+            let cloak = state.cloak().map(|c| c.cloak(client.state().hostname(), client.address().ip()));
+            let uncloaked = cloak.is_none() && client.set_mode(UserMode::Cloaked, false);
+            client.set_cloak(cloak);
+            let client = client.downgrade();
+            if uncloaked {
+                let mut msg: Message = Command::MODE(client.state().nick().to_owned(), Some("-x".to_owned()), None).into();
+                msg.prefix = Some(old.clone());
+                let _ = client.sender().send(msg);
+            }
+            let _ = handler::change_host(&state, &client, old).await;
+        }
     }
 
-    pub async fn wait_for_client(&mut self) -> Result<Socket<TcpStream>, ServerError> {
-        let mut iter: FuturesUnordered<_> = self.listeners.iter().map(|l| l.accept()).collect();
-        let conn = loop {
-            if let Some(c) = iter.next().await {
-                match c {
-                    Ok(val) => break val,
-                    Err(e) => eprintln!("{}", e),
+    /// Tells the operator who asked for a rehash how it went, or every
+    /// operator if it came from SIGHUP.
+    async fn report_rehash(&self, requester: Option<RehashRequest>, result: Result<Vec<ServerError>, ServerError>) {
+        let lines = match result {
+            Ok(failed) if failed.is_empty() => vec!["Configuration reloaded".to_owned()],
+            Ok(failed) => std::iter::once("Configuration reloaded, but some listeners failed to open".to_owned())
+                .chain(failed.iter().map(|e| e.to_string()))
+                .collect(),
+            Err(e) => vec![format!("Configuration not reloaded: {}", e)],
+        };
+        let state = self.state.read().await;
+        // Config errors can run over several lines.
+        for line in lines.iter().flat_map(|l| l.lines()).filter(|l| !l.trim().is_empty()) {
+            info!("{}", line);
+            match &requester {
+                Some(request) => {
+                    let mut msg: Message = Command::Notice(request.nick.as_str(), &format!("*** {}", line)).into();
+                    msg.set_prefix(state.get_name());
+                    let _ = request.sender.send(msg);
                 }
+                None => state.notice_opers(line).await,
             }
-        };
-        Ok(conn)
+        }
     }
 
     pub async fn run(&mut self) -> Result<(), ServerError> {
-        self.phase = ServerPhase::Running;
+        let mut hangup = signal(SignalKind::hangup()).map_err(ServerError::Io)?;
+        let mut terminate = signal(SignalKind::terminate()).map_err(ServerError::Io)?;
+        let mut interrupt = signal(SignalKind::interrupt()).map_err(ServerError::Io)?;
         loop {
-            let conn = self.wait_for_client().await.expect("Error accepting client");
+            let event = tokio::select! {
+                conn = self.accepted.recv() => Event::Accepted(conn),
+                _ = hangup.recv() => Event::Rehash(None),
+                Some(request) = self.rehash_requests.recv() => Event::Rehash(Some(request)),
+                _ = terminate.recv() => Event::Shutdown,
+                _ = interrupt.recv() => Event::Shutdown,
+            };
+            let conn = match event {
+                Event::Accepted(conn) => conn.ok_or(ServerError::ListenersClosed)?,
+                Event::Shutdown => {
+                    info!("Shutting down");
+                    return Ok(());
+                }
+                Event::Rehash(requester) => {
+                    let result = self.rehash().await;
+                    self.report_rehash(requester, result).await;
+                    continue;
+                }
+            };
             let resolver = self.resolver.clone();
+            let ident = self.ident.clone();
+            let dnsbl = self.dnsbl.clone();
+            let classes = self.classes.clone();
+            let webirc = self.webirc.clone();
             let server = self.state.clone();
             tokio::spawn(async move {
+                // Unix socket clients come with their hostname, and have no
+                // real address to look anything up for.
+                let local = conn.unix;
                 let mut client = Client::new(conn)
                     .await
                     .expect("Client construction failed");
+                // A gateway's connections start with WEBIRC, which has to be
+                // applied before anything is looked up for the address.
+                let mut pending = None;
+                let mut known_host = local.then(|| client.state().hostname().to_owned());
+                let mut via_gateway = false;
+                let gateway = webirc
+                    .iter()
+                    .find(|g| !local && g.hosts.iter().any(|net| net.contains(&client.address().ip())));
+                if let Some(gateway) = gateway {
+                    match tokio::time::timeout(WEBIRC_TIMEOUT, client.next_message()).await {
+                        Ok(Some(Ok(Message {
+                            contents: MessageContents::Command(Command::WEBIRC(password, _, hostname, ip, options)),
+                            ..
+                        }))) => match webirc_user(gateway, &password, &ip, options.as_deref()) {
+                            Ok((addr, secure)) => {
+                                let old = client.prefix();
+                                client.set_gateway_user(addr, secure);
+                                let _ = handler::change_host(&*server.read().await, &client, old).await;
+                                known_host = Some(hostname).filter(|h| dns::valid_hostname(h));
+                                via_gateway = true;
+                            }
+                            Err(reason) => {
+                                let _ = client.sender().send(Command::Error(format!("Closing link: {}", reason)));
+                                let _ = client.poll_send().await;
+                                server.read().await.notice_opers(&format!(
+                                    "Rejected WEBIRC from {} ({}): {}", gateway.name, client.address().ip(), reason
+                                )).await;
+                                return;
+                            }
+                        },
+                        Ok(Some(message)) => pending = Some(message),
+                        Ok(None) => return,
+                        // Not every client from a gateway host need be the gateway's.
+                        Err(_) => (),
+                    }
+                }
+                let check_ident = ident.enabled() && !via_gateway && !local;
                 server.read().await.send(&client, Command::Notice(
                         "*",
                         "*** Attempting lookup of your hostname...",
                     ))
                     .await
                     .expect("Failed to send message");
-                match resolver.reverse_lookup(client.address().ip()).await {
-                    Ok(val) => {
-                        let hostname = val
-                            .iter()
-                            .nth(0)
-                            .expect("Failed to get hostname even though i did");
+                if check_ident {
+                    server.read().await.send(&client, Command::Notice("*", "*** Checking Ident"))
+                        .await
+                        .expect("Failed to send message");
+                }
+                client.poll_send().await.expect("Failed to send message");
+                let (hostname, username, listings) = tokio::join!(
+                    async {
+                        match known_host.clone() {
+                            Some(hostname) => Ok(hostname),
+                            None => resolver.lookup(client.address().ip()).await,
+                        }
+                    },
+                    async {
+                        // Gateway and Unix socket users have no identd we could reach.
+                        if check_ident {
+                            ident.lookup(client.address(), client.local_address()).await
+                        } else {
+                            None
+                        }
+                    },
+                    async {
+                        if local {
+                            Vec::new()
+                        } else {
+                            dnsbl.check(client.address().ip()).await
+                        }
+                    },
+                );
+                if let Some(listing) = listings.iter().find(|l| l.action == DnsblAction::Reject) {
+                    let _ = client.sender().send(Command::Error(format!("Closing link: {}", listing.reason)));
+                    let _ = client.poll_send().await;
+                    server.read().await.notice_opers(&format!(
+                        "Rejected {} listed in {}", client.address().ip(), listing.zone
+                    )).await;
+                    return;
+                }
+                for listing in listings {
+                    match listing.action {
+                        DnsblAction::Mark => client.add_listing(listing.reason),
+                        DnsblAction::Notice => server.read().await.notice_opers(&format!(
+                            "Client from {} is listed in {}: {}", client.address().ip(), listing.zone, listing.reason
+                        )).await,
+                        DnsblAction::Reject => (),
+                    }
+                }
+                match hostname {
+                    Ok(hostname) => {
                         server.read().await.send(&client, Command::Notice(
                                 "*".to_owned(),
-                                format!("*** Found hostname using {}", hostname.to_string()),
+                                format!("*** Found hostname using {}", hostname),
                             ))
                             .await
                             .expect("Failed to send message");
+                        client.set_hostame(hostname);
                     }
                     Err(e) => {
                         server.read().await.send(&client, Command::Notice("*".to_owned(), format!("*** Lookup of hostname failed: {} using your ip address ({}) instead", e, client.address().ip()))).await.expect("Failed to send message");
                     }
                 }
+                if check_ident {
+                    let notice = match username {
+                        Some(_) => "*** Got Ident response",
+                        None => "*** No Ident response",
+                    };
+                    server.read().await.send(&client, Command::Notice("*", notice))
+                        .await
+                        .expect("Failed to send message");
+                }
+                if let Some(username) = username {
+                    client.set_ident(username);
+                }
+                if let Some(cloak) = server.read().await.cloak() {
+                    client.set_cloak(Some(cloak.cloak(client.state().hostname(), client.address().ip())));
+                    if cloak.by_default() {
+                        client.set_mode(UserMode::Cloaked, true);
+                    }
+                }
+                let state = client.state();
+                let info = ConnectionInfo {
+                    ip: client.address().ip(),
+                    host: state.hostname(),
+                    listener: client.listener(),
+                    tls: state.has_mode(UserMode::Secure),
+                };
+                let admitted = classes.admit(&info);
+                drop(state);
+                let ping_frequency = match admitted {
+                    Ok(class) => {
+                        let ping_frequency = Duration::from_secs(class.class().ping_frequency);
+                        client.set_class(class);
+                        ping_frequency
+                    }
+                    Err(reason) => {
+                        let _ = client.sender().send(Command::Error(format!("Closing link: {}", reason)));
+                        let _ = client.poll_send().await;
+                        return;
+                    }
+                };
                 client.poll_send().await.expect("Failed to send message");
-                println!("Entering registration loop");
+                debug!("Entering registration loop");
                 let mut stream = client.stream().expect("Failed to obtain client stream.");
-                let mut password: Option<String> = None;
                 let mut nick = String::new();
+                let mut user_info: Option<(String, String)> = None;
+                let mut cap_negotiating = false;
                 let result: Result<Arc<RwLock<Client>>, ProtocolError> = loop {
-                    if let Some(message) = stream.next().await {
+                    let next = match pending.take() {
+                        Some(message) => Some(message),
+                        None => stream.next().await,
+                    };
+                    if let Some(message) = next {
                         match message {
                             Ok(message) => {
-                                println!("Message: {:?}", message);
-                                match &message.contents {
-                                    MessageContents::Command(command) => match command {
-                                        Command::PASS(pass) => {
-                                            password = Some(pass.to_owned());
-                                        }
+                                trace!("Message: {:?}", message);
+                                let mut responder = Responder::new(&*server.read().await, &client, &message);
+                                if let MessageContents::Command(command) = &message.contents {
+                                    match command {
+                                        Command::PASS(_) => {}
                                         Command::NICK(nickname, _) => {
                                             if server.read().await.check_nick(nickname).await {
-                                                break_err!(server.read().await.send(&client, Response::ErrNickCollision(nickname.clone())).await);
+                                                responder.send(Response::ErrNickCollision(nickname.clone()));
+                                            } else {
+                                                nick = nickname.to_owned();
                                             }
-                                            nick = nickname.to_owned();
                                         }
                                         Command::USER(user, _, _, real) => {
-                                            match server.write().await.register_client(client, password, &nick, user, real).await {
-                                                Ok(v) => break Ok(v),
-                                                Err(e) => break Err(ProtocolError::ServerError)
-                                            };
+                                            user_info = Some((user.to_owned(), real.to_owned()));
+                                        }
+                                        Command::CAP(_, sub, arg, _) => {
+                                            // Registration is held until the client ends negotiation.
+                                            cap_negotiating = *sub != CapSubCommand::END;
+                                            server.read().await.handle_cap(&mut client, &mut responder, sub, arg.as_deref());
+                                        }
+                                        Command::AUTHENTICATE(data) => {
+                                            let account = handler::authenticate(&*server.read().await, &mut client, &mut responder, data);
+                                            if account.is_some() {
+                                                // The +r is announced with the other modes on registering.
+                                                client.set_account(account);
+                                                client.set_mode(UserMode::Registered, true);
+                                            }
                                         }
                                         Command::PONG(_, _) | Command::PING(_, _) => {}
                                         _ => {
-                                            break_err!(server.read().await.send(&client, Response::ErrNotRegistered).await);
+                                            responder.send(Response::ErrNotRegistered);
                                         }
-                                    },
-                                    _ => (),
+                                    }
                                 }
+                                if let Some((ref user, ref real)) = user_info {
+                                    if !nick.is_empty() && !cap_negotiating {
+                                        let isupport = server.read().await.isupport();
+                                        // Usernames identd didn't vouch for are marked with a ~.
+                                        let user = match client.state().ident() {
+                                            Some(ident) => ident.to_owned(),
+                                            None => format!("~{}", user),
+                                        };
+                                        let user: String = user.chars().take(USERLEN).collect();
+                                        let registered = server.write().await.register_client(client, &nick, &user, real).await;
+                                        match registered {
+                                            Ok(v) => {
+                                                responder.send(Response::RplWelcome(nick.clone(), format!("Welcome to the Internet Relay Network {}!{}", nick, user)));
+                                                responder.send(Response::RplISupport(nick.clone(), isupport));
+                                                let client = v.read().await;
+                                                if !client.state().modes().is_empty() {
+                                                    let modes = UserMode::to_string(client.state().modes());
+                                                    let mut msg: Message = Command::MODE(nick.clone(), Some(modes), None).into();
+                                                    msg.prefix = Some(client.prefix());
+                                                    responder.send(msg);
+                                                }
+                                                break_err!(responder.finish(&client));
+                                                drop(client);
+                                                break Ok(v)
+                                            }
+                                            Err(_) => break Err(ProtocolError::ServerError)
+                                        };
+                                    }
+                                }
+                                break_err!(responder.finish(&client));
                             }
                             Err(e) => match e {
                                 ProtocolError::InvalidMessage { string, cause } => match cause {
                                     MessageParseError::ErrResponse(r) => {
-                                        break_err!(server.read().await.send(&client, r).await);
+                                        break_err!(server.read().await.send(&client, *r).await);
                                     }
                                     _ => break Err(ProtocolError::InvalidMessage { string, cause })
                                 }
@@ -287,27 +1474,147 @@ impl Server {
                         }
                     }
                 };
-                if result.is_err() {
-                    eprintln!("Error: {}", result.unwrap_err());
-                    return;
+                let client = match result {
+                    Ok(client) => client,
+                    Err(e) => {
+                        info!("Error: {}", e);
+                        return;
+                    }
+                };
+                let mut quit_reason = None;
+                let mut awaiting_pong = false;
+                let result: Result<(), ProtocolError> = loop {
+                    // Anything from the client shows it is still there, so it is
+                    // only pinged after a quiet period.
+                    let message = match tokio::time::timeout(ping_frequency, stream.next()).await {
+                        Ok(message) => {
+                            awaiting_pong = false;
+                            message
+                        }
+                        Err(_) if awaiting_pong => break Err(ProtocolError::PingTimeout),
+                        Err(_) => {
+                            awaiting_pong = true;
+                            let ping = Command::Ping(server.read().await.get_name().to_owned(), None);
+                            break_err!(client.read().await.sender().send(ping));
+                            continue;
+                        }
+                    };
+                    match message {
+                        Some(Ok(message)) => {
+                            if let MessageContents::Command(Command::QUIT(reason)) = message.contents {
+                                quit_reason = reason;
+                                break Ok(());
+                            }
+                            break_err!(handler::dispatch(&server, &client, message).await);
+                        }
+                        Some(Err(e)) => match e {
+                            ProtocolError::InvalidMessage { cause: MessageParseError::ErrResponse(r), .. } => {
+                                let client = client.read().await;
+                                // The parser can't know the nick of who sent the line.
+                                let r = match *r {
+                                    Response::ErrInputTooLong(_) => Response::ErrInputTooLong(client.state().nick().to_owned()),
+                                    r => r,
+                                };
+                                break_err!(server.read().await.send(&client, r).await);
+                            }
+                            _ => break Err(e)
+                        }
+                        None => break Ok(()),
+                    }
+                };
+                if let Err(ref e) = result {
+                    info!("Error: {}", e);
+                    quit_reason = Some(e.to_string());
                 }
-                let client = result.unwrap();
+                let nick = client.read().await.state().nick().to_owned();
+                server.write().await.remove_client(&nick, quit_reason).await;
             });
         }
         //Ok(())
     }
 }
 
+/// The address and TLS status a gateway's `WEBIRC` gives for its user, or
+/// why it was refused.
+fn webirc_user(gateway: &WebIrc, password: &str, ip: &str, options: Option<&str>) -> Result<(SocketAddr, bool), &'static str> {
+    if !secrets_match(password, &gateway.password) {
+        return Err("Invalid WEBIRC password");
+    }
+    let ip: IpAddr = ip.parse().map_err(|_| "Invalid WEBIRC address")?;
+    let mut port = 0;
+    let mut secure = false;
+    for option in options.unwrap_or("").split(' ') {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+        match key {
+            "secure" => secure = true,
+            "remote-port" => port = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+    Ok((SocketAddr::new(ip, port), secure))
+}
+
+/// Compares secrets in a time that doesn't depend on where they first differ,
+/// so guesses can't be refined a byte at a time.
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 #[derive(Debug, Error)]
 pub enum ServerError {
-    #[error("listener modification error: {0}")]
-    ListenerModification(String),
-    #[error("tls error: {}", string)]
+    #[error("{0}")]
+    Config(#[from] ConfigErrors),
+    #[error("tls error on listener {listener}: {cause}")]
     Tls {
-        string: String,
+        listener: String,
         #[source]
-        cause: Option<native_tls::Error>,
+        cause: TlsError,
+    },
+    #[error("failed to open listener {listener}: {cause}")]
+    Bind {
+        listener: String,
+        #[source]
+        cause: io::Error,
     },
     #[error("IO error {0}")]
     Io(#[source] io::Error),
+    #[error("DNS resolver error: {0}")]
+    Resolver(#[source] ResolveError),
+    #[error("every listener has stopped")]
+    ListenersClosed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway() -> WebIrc {
+        WebIrc {
+            name: "kiwi".to_owned(),
+            password: "hunter2".to_owned(),
+            hosts: vec!["127.0.0.1/32".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter2", "hunter3"));
+        assert!(!secrets_match("hunter2", "hunter"));
+        assert!(!secrets_match("", "hunter2"));
+    }
+
+    #[test]
+    fn accepts_webirc_user() {
+        let user = webirc_user(&gateway(), "hunter2", "192.0.2.1", Some("secure remote-port=5000"));
+        assert_eq!(user, Ok(("192.0.2.1:5000".parse().unwrap(), true)));
+        let user = webirc_user(&gateway(), "hunter2", "2001:db8::1", None);
+        assert_eq!(user, Ok(("[2001:db8::1]:0".parse().unwrap(), false)));
+    }
+
+    #[test]
+    fn rejects_webirc_user() {
+        assert_eq!(webirc_user(&gateway(), "hunter3", "192.0.2.1", None), Err("Invalid WEBIRC password"));
+        assert_eq!(webirc_user(&gateway(), "hunter2", "not-an-ip", None), Err("Invalid WEBIRC address"));
+    }
 }
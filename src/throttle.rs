@@ -0,0 +1,139 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+
+use crate::config;
+
+/// How many connections are checked between sweeps of the whole history for
+/// networks which have gone quiet.
+const SWEEP_INTERVAL: usize = 1024;
+
+/// Limits how many connections an address or network may open per window.
+/// This is checked as soon as a connection is accepted, so throttled clients
+/// never cost the server a TLS handshake.
+#[derive(Debug)]
+pub struct Throttle {
+    connections: usize,
+    window: Duration,
+    cidr_v4: u8,
+    cidr_v6: u8,
+    exempt: Vec<IpNet>,
+    handshake_timeout: Duration,
+    /* Network to the times of its recent connections, oldest first */
+    history: Mutex<HashMap<IpNet, VecDeque<Instant>>>,
+    /* Connections checked since the history was last swept */
+    checked: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn new(config: &config::Throttle) -> Throttle {
+        Throttle {
+            connections: config.connections,
+            window: Duration::from_secs(config.window),
+            cidr_v4: config.cidr_v4,
+            cidr_v6: config.cidr_v6,
+            exempt: config.exempt.clone(),
+            handshake_timeout: Duration::from_secs(config.handshake_timeout),
+            history: Mutex::new(HashMap::new()),
+            checked: AtomicUsize::new(0),
+        }
+    }
+
+    /// A throttle with new settings which remembers this one's connections.
+    pub fn reconfigured(&self, config: &config::Throttle) -> Throttle {
+        let history = std::mem::take(&mut *self.history.lock().expect("throttle history poisoned"));
+        Throttle {
+            history: Mutex::new(history),
+            ..Throttle::new(config)
+        }
+    }
+
+    /// How long a connection has to finish its PROXY, TLS and WebSocket
+    /// handshakes.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Records a connection from `ip`, returning false if it is over the limit.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.exempt.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        let prefix = match ip {
+            IpAddr::V4(_) => self.cidr_v4,
+            IpAddr::V6(_) => self.cidr_v6,
+        };
+        let net = IpNet::new(ip, prefix)
+            .map(|net| net.trunc())
+            .unwrap_or_else(|_| IpNet::from(ip));
+        let now = Instant::now();
+        let mut history = self.history.lock().expect("throttle history poisoned");
+        if self.checked.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            history.retain(|_, times| {
+                self.expire(times, now);
+                !times.is_empty()
+            });
+        }
+        let times = history.entry(net).or_default();
+        self.expire(times, now);
+        if times.len() >= self.connections {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Forgets connections which have left the window.
+    fn expire(&self, times: &mut VecDeque<Instant>, now: Instant) {
+        while times.front().is_some_and(|t| now.duration_since(*t) >= self.window) {
+            times.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(connections: usize, window: u64) -> Throttle {
+        Throttle::new(&config::Throttle {
+            connections,
+            window,
+            ..config::Throttle::default()
+        })
+    }
+
+    #[test]
+    fn limits_each_network() {
+        let throttle = throttle(2, 60);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(throttle.allow(ip));
+        assert!(throttle.allow(ip));
+        assert!(!throttle.allow(ip));
+        assert!(throttle.allow("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn forgets_connections_outside_the_window() {
+        let throttle = throttle(1, 0);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(throttle.allow(ip));
+        assert!(throttle.allow(ip));
+    }
+
+    #[test]
+    fn sweeps_quiet_networks() {
+        let throttle = throttle(1, 0);
+        for i in 0..SWEEP_INTERVAL {
+            let ip = IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]);
+            assert!(throttle.allow(ip));
+        }
+        // Only the connection recorded after the sweep remains.
+        assert_eq!(throttle.history.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use std::fs::read;
+use std::io;
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::native_tls::{self, Identity, Protocol};
+
+use crate::config::{TLSCert, TlsBackend, TlsVersion};
+use crate::tls_socket::Socket;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {cause}")]
+    Read {
+        path: String,
+        #[source]
+        cause: io::Error,
+    },
+    #[error("{0}")]
+    Native(#[from] native_tls::Error),
+    #[cfg(feature = "rustls")]
+    #[error("{0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+    #[cfg(feature = "rustls")]
+    #[error("{0}")]
+    Handshake(#[source] io::Error),
+    #[error("{0}")]
+    Config(String),
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, TlsError> {
+    read(path).map_err(|cause| TlsError::Read {
+        path: path.to_owned(),
+        cause,
+    })
+}
+
+/// Completes the server side of TLS handshakes with whichever backend a
+/// listener is configured for.
+#[derive(Clone)]
+pub enum Acceptor {
+    Native(tokio_native_tls::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsAcceptor, std::sync::Arc<rustls::Certificates>),
+}
+
+impl std::fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Acceptor::Native(_) => f.write_str("Acceptor::Native"),
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(..) => f.write_str("Acceptor::Rustls"),
+        }
+    }
+}
+
+impl Acceptor {
+    pub fn new(config: &TLSCert) -> Result<Acceptor, TlsError> {
+        match config.backend {
+            TlsBackend::Native => Acceptor::new_native(config),
+            #[cfg(feature = "rustls")]
+            TlsBackend::Rustls => rustls::acceptor(config),
+            #[cfg(not(feature = "rustls"))]
+            TlsBackend::Rustls => Err(TlsError::Config(
+                "the rustls backend needs cawcaw to be built with the rustls feature".to_owned(),
+            )),
+        }
+    }
+
+    fn new_native(config: &TLSCert) -> Result<Acceptor, TlsError> {
+        if !config.sni.is_empty() || config.reload != 0 || config.client_certs {
+            return Err(TlsError::Config(
+                "SNI certificates, reloading and client certificates need the rustls backend".to_owned(),
+            ));
+        }
+        let min_version = match config.min_version {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => {
+                return Err(TlsError::Config(
+                    "a minimum version of TLS 1.3 needs the rustls backend".to_owned(),
+                ))
+            }
+        };
+        let cert = read_file(&config.cert)?;
+        let key = read_file(&config.key)?;
+        let identity = Identity::from_pkcs8(&cert, &key)?;
+        let acceptor = native_tls::TlsAcceptor::builder(identity)
+            .min_protocol_version(Some(min_version))
+            .build()?;
+        Ok(Acceptor::Native(acceptor.into()))
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> Result<Socket<S>, TlsError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Acceptor::Native(acceptor) => Ok(Socket::Tls(acceptor.accept(stream).await?)),
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(acceptor, _) => acceptor
+                .accept(stream)
+                .await
+                .map(|stream| Socket::Rustls(Box::new(stream)))
+                .map_err(TlsError::Handshake),
+        }
+    }
+
+    /// Reloads the certificates whenever their files change, checking every
+    /// `reload` seconds as configured. Returns straight away for backends
+    /// which can't swap certificates.
+    #[cfg_attr(not(feature = "rustls"), allow(unused_variables))]
+    pub async fn watch(self, config: TLSCert) {
+        match self {
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(_, certificates) if config.reload != 0 => {
+                let interval = std::time::Duration::from_secs(config.reload);
+                certificates.watch(&config, interval).await
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod rustls {
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use arc_swap::ArcSwap;
+    use log::{error, info};
+    use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+    use tokio_rustls::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, UnixTime};
+    use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+    use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+    use tokio_rustls::rustls::sign::CertifiedKey;
+    use tokio_rustls::rustls::version::{TLS12, TLS13};
+    use tokio_rustls::rustls::{DigitallySignedStruct, DistinguishedName, Error, ServerConfig, SignatureScheme};
+
+    use super::{read_file, Acceptor, TlsError};
+    use crate::config::{TLSCert, TlsVersion};
+
+    pub fn acceptor(config: &TLSCert) -> Result<Acceptor, TlsError> {
+        let versions: &[_] = match config.min_version {
+            TlsVersion::Tls13 => &[&TLS13],
+            TlsVersion::Tls12 => &[&TLS13, &TLS12],
+            _ => {
+                return Err(TlsError::Config(
+                    "the rustls backend supports TLS 1.2 and later only".to_owned(),
+                ))
+            }
+        };
+        let certificates = Arc::new(Certificates::load(config)?);
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(versions)?;
+        let builder = if config.client_certs {
+            builder.with_client_cert_verifier(Arc::new(AnyClientCert {
+                algorithms: provider.signature_verification_algorithms,
+            }))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let server_config = builder.with_cert_resolver(certificates.clone());
+        Ok(Acceptor::Rustls(Arc::new(server_config).into(), certificates))
+    }
+
+    /// Accepts any client certificate, or none. The certificate only serves
+    /// to identify the client by its fingerprint, so there's no chain to
+    /// check; the handshake signature still proves the client holds its key.
+    #[derive(Debug)]
+    struct AnyClientCert {
+        algorithms: WebPkiSupportedAlgorithms,
+    }
+
+    impl ClientCertVerifier for AnyClientCert {
+        fn offer_client_auth(&self) -> bool {
+            true
+        }
+
+        fn client_auth_mandatory(&self) -> bool {
+            false
+        }
+
+        fn root_hint_subjects(&self) -> &[DistinguishedName] {
+            &[]
+        }
+
+        fn verify_client_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _now: UnixTime,
+        ) -> Result<ClientCertVerified, Error> {
+            Ok(ClientCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    fn certified_key(cert: &str, key: &str) -> Result<Arc<CertifiedKey>, TlsError> {
+        let chain = rustls_pemfile::certs(&mut BufReader::new(&read_file(cert)?[..]))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|cause| TlsError::Read {
+                path: cert.to_owned(),
+                cause,
+            })?;
+        if chain.is_empty() {
+            return Err(TlsError::Config(format!("no certificates in {}", cert)));
+        }
+        let key_der = rustls_pemfile::private_key(&mut BufReader::new(&read_file(key)?[..]))
+            .map_err(|cause| TlsError::Read {
+                path: key.to_owned(),
+                cause,
+            })?
+            .ok_or_else(|| TlsError::Config(format!("no private key in {}", key)))?;
+        let signing_key = ring::sign::any_supported_type(&key_der)?;
+        Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+    }
+
+    #[derive(Debug)]
+    struct CertSet {
+        default: Arc<CertifiedKey>,
+        /* Lowercased SNI hostname to its certificate */
+        by_name: HashMap<String, Arc<CertifiedKey>>,
+    }
+
+    impl CertSet {
+        fn load(config: &TLSCert) -> Result<CertSet, TlsError> {
+            let mut by_name = HashMap::new();
+            for sni in &config.sni {
+                let key = certified_key(&sni.cert, &sni.key)?;
+                for name in &sni.hostnames {
+                    by_name.insert(name.to_ascii_lowercase(), key.clone());
+                }
+            }
+            Ok(CertSet {
+                default: certified_key(&config.cert, &config.key)?,
+                by_name,
+            })
+        }
+    }
+
+    /// A listener's certificates, which can be replaced while it runs.
+    #[derive(Debug)]
+    pub struct Certificates {
+        current: ArcSwap<CertSet>,
+    }
+
+    impl Certificates {
+        fn load(config: &TLSCert) -> Result<Certificates, TlsError> {
+            Ok(Certificates {
+                current: ArcSwap::from_pointee(CertSet::load(config)?),
+            })
+        }
+
+        /// Checks the certificate and key files every `interval` and loads
+        /// them again when any has changed. New handshakes use the new
+        /// certificates; if they fail to load the old ones stay in use.
+        pub async fn watch(&self, config: &TLSCert, interval: Duration) {
+            let mut modified = modified_times(config);
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = modified_times(config);
+                if now == modified {
+                    continue;
+                }
+                match CertSet::load(config) {
+                    Ok(set) => {
+                        self.current.store(Arc::new(set));
+                        modified = now;
+                        info!("Reloaded TLS certificate {}", config.cert);
+                    }
+                    // Leave `modified` alone so a half-written renewal is retried.
+                    Err(e) => error!("Failed to reload TLS certificate {}: {}", config.cert, e),
+                }
+            }
+        }
+    }
+
+    fn modified_times(config: &TLSCert) -> Vec<Option<SystemTime>> {
+        let files = [&config.cert, &config.key]
+            .into_iter()
+            .chain(config.sni.iter().flat_map(|sni| [&sni.cert, &sni.key]));
+        files
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    impl ResolvesServerCert for Certificates {
+        fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            let set = self.current.load();
+            let named = client_hello
+                .server_name()
+                .and_then(|name| set.by_name.get(&name.to_ascii_lowercase()));
+            Some(named.unwrap_or(&set.default).clone())
+        }
+    }
+}
@@ -1,13 +1,105 @@
-use std::net::SocketAddr;
+use std::pin::Pin;
 
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
 use tokio_native_tls::TlsStream;
+
+use crate::websocket::WsStream;
+
+/// The connection a client's socket is carried over.
+#[derive(Debug)]
+#[pin_project(project = NetStreamProj)]
+pub enum NetStream {
+    Tcp(#[pin] TcpStream),
+    Unix(#[pin] UnixStream),
+}
+
+impl AsyncRead for NetStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.project() {
+            NetStreamProj::Tcp(stream) => stream.poll_read(cx, buf),
+            NetStreamProj::Unix(stream) => stream.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for NetStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        match self.project() {
+            NetStreamProj::Tcp(stream) => stream.poll_write(cx, buf),
+            NetStreamProj::Unix(stream) => stream.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.project() {
+            NetStreamProj::Tcp(stream) => stream.poll_flush(cx),
+            NetStreamProj::Unix(stream) => stream.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        match self.project() {
+            NetStreamProj::Tcp(stream) => stream.poll_shutdown(cx),
+            NetStreamProj::Unix(stream) => stream.poll_shutdown(cx),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[pin_project(project = SocketProj)]
 pub enum Socket<S> {
     Plain(#[pin] S),
     Tls(#[pin] TlsStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<tokio_rustls::server::TlsStream<S>>),
+    Ws(Box<WsStream<Socket<S>>>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socket<S> {
+    pub fn is_tls(&self) -> bool {
+        match self {
+            Socket::Plain(_) => false,
+            Socket::Ws(socket) => socket.get_ref().is_tls(),
+            _ => true,
+        }
+    }
+
+    /// The DER-encoded certificate the client presented, if any.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        match self {
+            Socket::Plain(_) => None,
+            Socket::Tls(socket) => socket
+                .get_ref()
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|cert| cert.to_der().ok()),
+            #[cfg(feature = "rustls")]
+            Socket::Rustls(socket) => socket
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|cert| cert.to_vec()),
+            Socket::Ws(socket) => socket.get_ref().peer_certificate(),
+        }
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Socket<S> {
@@ -19,6 +111,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Socket<S> {
         match self.project() {
             SocketProj::Plain(socket) => socket.poll_read(cx, buf),
             SocketProj::Tls(socket) => socket.poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            SocketProj::Rustls(socket) => Pin::new(&mut **socket).poll_read(cx, buf),
+            SocketProj::Ws(socket) => Pin::new(&mut **socket).poll_read(cx, buf),
         }
     }
 }
@@ -32,6 +127,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
         match self.project() {
             SocketProj::Plain(socket) => socket.poll_write(cx, buf),
             SocketProj::Tls(socket) => socket.poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            SocketProj::Rustls(socket) => Pin::new(&mut **socket).poll_write(cx, buf),
+            SocketProj::Ws(socket) => Pin::new(&mut **socket).poll_write(cx, buf),
         }
     }
 
@@ -42,6 +140,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
         match self.project() {
             SocketProj::Plain(socket) => socket.poll_flush(cx),
             SocketProj::Tls(socket) => socket.poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            SocketProj::Rustls(socket) => Pin::new(&mut **socket).poll_flush(cx),
+            SocketProj::Ws(socket) => Pin::new(&mut **socket).poll_flush(cx),
         }
     }
 
@@ -52,6 +153,9 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socket<S> {
         match self.project() {
             SocketProj::Plain(socket) => socket.poll_shutdown(cx),
             SocketProj::Tls(socket) => socket.poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            SocketProj::Rustls(socket) => Pin::new(&mut **socket).poll_shutdown(cx),
+            SocketProj::Ws(socket) => Pin::new(&mut **socket).poll_shutdown(cx),
         }
     }
 }
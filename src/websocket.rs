@@ -0,0 +1,170 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::class::mask_matches;
+use crate::config::WebSocket;
+
+/// Subprotocol carrying each line as a text frame.
+const TEXT_PROTOCOL: &str = "text.ircv3.net";
+/// Subprotocol carrying each line as a binary frame, for non-UTF-8 encodings.
+const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+/// Longest frame accepted: a full line of tags plus a full message.
+const MAX_FRAME_SIZE: usize = 8191 + 512;
+
+/// Completes the WebSocket upgrade of `stream`, refusing browsers whose
+/// `Origin` isn't allowed. Clients which don't send an `Origin` aren't
+/// browsers and are always let in.
+pub async fn accept<S>(stream: S, config: &WebSocket) -> Result<WsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut binary = false;
+    // The error type is fixed by tungstenite's `Callback`.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        if let Some(origin) = request.headers().get("Origin") {
+            let origin = origin.to_str().unwrap_or("");
+            if !config.origins.is_empty() && !config.origins.iter().any(|mask| mask_matches(mask, origin)) {
+                let mut error = ErrorResponse::new(Some("Origin not allowed".to_owned()));
+                *error.status_mut() = StatusCode::FORBIDDEN;
+                return Err(error);
+            }
+        }
+        // The first of the client's subprotocols which we support.
+        let protocol = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .find(|p| *p == TEXT_PROTOCOL || *p == BINARY_PROTOCOL);
+        if let Some(protocol) = protocol {
+            binary = protocol == BINARY_PROTOCOL;
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(if binary { BINARY_PROTOCOL } else { TEXT_PROTOCOL }));
+        }
+        Ok(response)
+    };
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+        ..Default::default()
+    };
+    let inner = tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(ws_config)).await?;
+    Ok(WsStream {
+        inner,
+        binary,
+        read_buf: Vec::new(),
+        read_pos: 0,
+        write_buf: Vec::new(),
+    })
+}
+
+fn to_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// A WebSocket connection seen as a byte stream of IRC lines, so it can sit
+/// under the same codec as a TCP connection. Each frame read becomes one
+/// CRLF-terminated line and each line written is sent as one frame.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /* Whether lines are sent as binary frames rather than text */
+    binary: bool,
+    /* The line being read, and how much of it has been */
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /* Written bytes not yet sent as frames */
+    write_buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Sends every complete line in the write buffer as a frame.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(end) = self.write_buf.iter().position(|b| *b == b'\n') {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io)?;
+            let mut line: Vec<u8> = self.write_buf.drain(..=end).collect();
+            while line.last().is_some_and(|b| *b == b'\r' || *b == b'\n') {
+                line.pop();
+            }
+            let frame = if self.binary {
+                Message::Binary(line)
+            } else {
+                Message::Text(String::from_utf8_lossy(&line).into_owned())
+            };
+            Pin::new(&mut self.inner).start_send(frame).map_err(to_io)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.read_pos == this.read_buf.len() {
+            let mut line = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+            };
+            while line.last().is_some_and(|b| *b == b'\r' || *b == b'\n') {
+                line.pop();
+            }
+            if line.is_empty() {
+                continue;
+            }
+            line.extend_from_slice(b"\r\n");
+            this.read_buf = line;
+            this.read_pos = 0;
+        }
+        let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Frames go out on flush, which the codec does after every write.
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_lines(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(to_io)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_lines(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx).map_err(to_io)
+    }
+}